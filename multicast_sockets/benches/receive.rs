@@ -0,0 +1,160 @@
+//! Compare the `recvmmsg` and io_uring receive paths on `lo`.
+//!
+//! Run with `cargo bench --bench receive`. For every backend, a sender
+//! thread sends datagrams to a group on `lo` as fast as `sendmmsg` allows,
+//! while the receiver thread receives them the way the receiver binary does.
+//! The rate, the loss and the CPU time spent by the receiver thread are
+//! printed per backend.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::fd::RawFd,
+    thread,
+    time::{Duration, Instant},
+};
+
+use multicast_sockets::{
+    membership::{self, Membership},
+    packet::{self, Header},
+    receive::{self, set_receive_buffer, BatchReceiver, Metadata},
+    send::{self, BatchSender, PacketInfo},
+    uring::UringReceiver,
+};
+use nix::{
+    errno::Errno,
+    net::if_::if_nametoindex,
+    sys::{
+        resource::{getrusage, UsageWho},
+        socket::AddressFamily,
+        time::TimeValLike,
+    },
+    unistd::close,
+};
+
+/// Datagrams sent per backend.
+const COUNT: usize = 500_000;
+/// Datagrams per `sendmmsg` call, and per `recvmmsg` call or buffers in the
+/// buffer ring.
+const BATCH: usize = 64;
+/// Pause of the receiver when no datagrams are available.
+const PERIOD: Duration = Duration::from_millis(1);
+/// The receiver stops once nothing arrived for this long.
+const IDLE: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Recvmmsg,
+    IoUring,
+}
+
+struct Measurement {
+    received: usize,
+    elapsed: Duration,
+    cpu: Duration,
+}
+
+fn main() {
+    let interface_index = if_nametoindex("lo").expect("no loopback interface");
+
+    for (backend, port) in [(Backend::Recvmmsg, 30212), (Backend::IoUring, 30213)] {
+        let group = SocketAddr::new(Ipv4Addr::new(239, 255, 20, 12).into(), port);
+        let socket = receive::bind_socket(AddressFamily::Inet, port).unwrap();
+        set_receive_buffer(socket, 4 << 20).unwrap();
+        membership::join(socket, &Membership::any_source(group.ip(), interface_index)).unwrap();
+
+        let receiver = thread::spawn(move || receive(backend, socket));
+        let sent = send(group, interface_index);
+        let result = receiver.join().unwrap();
+        close(socket).unwrap();
+
+        let seconds = result.elapsed.as_secs_f64();
+        println!(
+            "{backend:?}: received {} of {sent} datagrams ({:.3}% lost) in {:.3}s, \
+             {:.0} pkt/s, {:.0} ns CPU per datagram",
+            result.received,
+            (sent - result.received) as f64 * 100.0 / sent as f64,
+            seconds,
+            result.received as f64 / seconds,
+            result.cpu.as_nanos() as f64 / result.received.max(1) as f64,
+        );
+    }
+}
+
+fn send(group: SocketAddr, interface_index: u32) -> usize {
+    let socket = send::bind_socket(AddressFamily::Inet, 0).unwrap();
+    send::set_multicast_loop(socket, AddressFamily::Inet, true).unwrap();
+    let sender = BatchSender::new(
+        socket,
+        group,
+        PacketInfo::new(IpAddr::V4(Ipv4Addr::LOCALHOST), interface_index),
+    );
+    // Give the receiver time to wait for the first datagram.
+    thread::sleep(Duration::from_millis(50));
+
+    let mut sent = 0;
+    let mut datagrams = vec![Vec::new(); BATCH];
+    while sent < COUNT {
+        for (idx, datagram) in datagrams.iter_mut().enumerate() {
+            let header = Header {
+                sequence: (sent + idx) as u32,
+                ..Default::default()
+            };
+            datagram.clear();
+            packet::encode(&header, &[0; 64], datagram);
+        }
+        match sender.send(&datagrams) {
+            Ok(count) => sent += count,
+            Err(Errno::EAGAIN | Errno::ENOBUFS) => thread::yield_now(),
+            Err(e) => panic!("failed to send: {e}"),
+        }
+    }
+
+    close(socket).unwrap();
+    sent
+}
+
+fn receive(backend: Backend, socket: RawFd) -> Measurement {
+    let mut received = 0;
+    let mut first = None;
+    let mut last = Instant::now();
+    let cpu_before = thread_cpu_time();
+
+    let mut count = |_: &[u8], _: &Metadata| received += 1;
+    let mut batch = BatchReceiver::new(BATCH, 1400);
+    let mut uring = match backend {
+        Backend::Recvmmsg => None,
+        Backend::IoUring => Some(UringReceiver::new(socket, BATCH, 1400).unwrap()),
+    };
+
+    // Wait for the first datagram, then until the sender is done.
+    while first.is_none() || last.elapsed() < IDLE {
+        let result = match &mut uring {
+            Some(uring) => uring.recv(PERIOD, &mut count),
+            None => batch.recv(socket, &mut count),
+        };
+        match result {
+            Ok(0) | Err(Errno::EAGAIN) => {
+                if uring.is_none() {
+                    thread::sleep(PERIOD);
+                }
+            }
+            Ok(_) => {
+                last = Instant::now();
+                first.get_or_insert(last);
+            }
+            Err(e) => panic!("failed to receive: {e}"),
+        }
+    }
+
+    Measurement {
+        received,
+        elapsed: last - first.unwrap_or(last),
+        cpu: thread_cpu_time() - cpu_before,
+    }
+}
+
+fn thread_cpu_time() -> Duration {
+    let usage = getrusage(UsageWho::RUSAGE_THREAD).unwrap();
+    let micros = (usage.user_time() + usage.system_time()).num_microseconds();
+    Duration::from_micros(micros as u64)
+}
@@ -0,0 +1,56 @@
+//! Receive multicast datagrams with the tokio based `MulticastReceiver`.
+//!
+//! Run with `cargo run --example async_receiver -- --group-addr ff14::1a`.
+
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use multicast_sockets::{
+    address_family,
+    async_receiver::MulticastReceiver,
+    interface::{select_interface, InterfaceSelector},
+    membership::Membership,
+    packet,
+};
+
+#[derive(Parser)]
+pub struct Cli {
+    /// Group address (without port) to listen to.
+    #[arg(long, default_value = "ff14::1a")]
+    pub group_addr: IpAddr,
+
+    /// Group port.
+    #[arg(long, default_value_t = 30000)]
+    pub group_port: u16,
+
+    /// Interface to join on, by name, index, address or `default`.
+    #[arg(long, default_value = "eth0")]
+    pub interface: InterfaceSelector,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+
+    let interface = select_interface(&args.interface)?;
+    let membership = Membership::any_source(args.group_addr, interface.index);
+    let mut receiver = MulticastReceiver::bind(
+        address_family(&args.group_addr),
+        args.group_port,
+        &[membership],
+    )
+    .context("failed to bind receiver")?;
+
+    loop {
+        let datagram = receiver.recv().await?;
+        let source = datagram.meta.source;
+        match packet::decode(&datagram.payload) {
+            Ok(packet) => println!(
+                "Packet {} of stream {} from {source:?} on interface {:?}",
+                packet.header.sequence, packet.header.stream_id, datagram.meta.interface_index
+            ),
+            Err(e) => println!("Invalid datagram from {source:?}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Receiving datagrams in tokio applications.
+//!
+//! [`MulticastReceiver`] registers a non-blocking socket with tokio's
+//! [`AsyncFd`] and receives a batch with `recvmmsg` whenever the socket
+//! becomes readable, instead of polling it with a fixed sleep.
+
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use nix::sys::socket::AddressFamily;
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::{
+    membership::{self, Membership},
+    receive::{bind_socket, BatchReceiver, Metadata},
+};
+
+pub use crate::receive::{DEFAULT_BUFFER_SIZE, DEFAULT_FRAMES};
+
+/// A received datagram with its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub payload: Bytes,
+    pub meta: Metadata,
+}
+
+/// Asynchronous multicast receiver yielding a [`Stream`] of [`Datagram`]s.
+#[derive(Debug)]
+pub struct MulticastReceiver {
+    fd: AsyncFd<OwnedFd>,
+    batch: BatchReceiver,
+    /// Datagrams of the last batch which were not yet yielded.
+    pending: VecDeque<Datagram>,
+}
+
+impl MulticastReceiver {
+    /// Bind a socket of `family` to `port` and join all `memberships`.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn bind(family: AddressFamily, port: u16, memberships: &[Membership]) -> io::Result<Self> {
+        let socket = bind_socket(family, port)?;
+        // SAFETY:
+        // The socket was just opened and is not owned by anything else.
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        let receiver = Self::from_socket(socket)?;
+        for membership in memberships {
+            receiver.join(membership)?;
+        }
+        Ok(receiver)
+    }
+
+    /// Use an already configured socket, which has to be non-blocking.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn from_socket(socket: OwnedFd) -> io::Result<Self> {
+        // SAFETY:
+        // The `OwnedFd` keeps the descriptor open and unchanged until it is
+        // dropped together with the `AsyncFd`.
+        let fd = unsafe { AsyncFd::register_with_interest(socket, Interest::READABLE) }
+            .map_err(io::Error::from)?;
+
+        Ok(Self {
+            fd,
+            batch: BatchReceiver::new(DEFAULT_FRAMES, DEFAULT_BUFFER_SIZE),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Receive batches of up to `frames` datagrams with up to `buffer_size`
+    /// bytes each.
+    pub fn with_batch(mut self, frames: usize, buffer_size: usize) -> Self {
+        self.batch = BatchReceiver::new(frames, buffer_size);
+        self
+    }
+
+    /// Join a group while receiving.
+    pub fn join(&self, membership: &Membership) -> io::Result<()> {
+        membership::join(self.fd.get_ref().as_raw_fd(), membership)?;
+        Ok(())
+    }
+
+    /// Leave a group while receiving.
+    pub fn leave(&self, membership: &Membership) -> io::Result<()> {
+        membership::leave(self.fd.get_ref().as_raw_fd(), membership)?;
+        Ok(())
+    }
+
+    /// Receive the next datagram.
+    pub async fn recv(&mut self) -> io::Result<Datagram> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .expect("receiver stream never ends")
+    }
+}
+
+impl Stream for MulticastReceiver {
+    type Item = io::Result<Datagram>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(datagram) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+
+            let mut guard = match ready!(this.fd.poll_read_ready(cx)) {
+                Ok(guard) => guard,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+
+            let batch = &mut this.batch;
+            let pending = &mut this.pending;
+            let result = guard.try_io(|fd| {
+                batch
+                    .recv(fd.get_ref().as_raw_fd(), |payload, meta| {
+                        pending.push_back(Datagram {
+                            payload: Bytes::copy_from_slice(payload),
+                            meta: *meta,
+                        })
+                    })
+                    .map_err(io::Error::from)
+            });
+
+            match result {
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                // The socket was not readable after all, readiness is cleared
+                // and polled again.
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
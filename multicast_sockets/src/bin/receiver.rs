@@ -3,34 +3,91 @@
 //! Run with `cargo run --target aarch64-linux-android --release --bin receiver`.
 
 use std::{
-    io::IoSliceMut,
-    net::{Ipv6Addr, SocketAddrV6},
-    time::Duration,
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    num::NonZeroUsize,
+    os::fd::RawFd,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use bytes::Buf;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 use humantime::parse_duration;
-use multicast_sockets::get_interface_by_name;
-use nix::{
-    cmsg_space,
-    errno::Errno,
-    sys::{
-        socket::{
-            bind, recvmmsg, setsockopt, socket, sockopt, AddressFamily, Ipv6MembershipRequest,
-            MsgFlags, MultiHeaders, SockFlag, SockType, SockaddrIn, SockaddrIn6,
-        },
-        time::TimeVal,
+#[cfg(feature = "io-uring")]
+use multicast_sockets::uring::UringReceiver;
+use multicast_sockets::{
+    address_family,
+    control::{ControlChannel, ControlCommand, ControlSource},
+    crypto::{session_id, PresharedKey, ReplayGuard},
+    fanout::{bind_worker_socket, buffer_drops, pin_thread, Steering},
+    fec::{FecDecoder, Recovered},
+    fragment::{Reassembler, DEFAULT_MEMORY_LIMIT},
+    groups::{joined_groups, missing, print_joined_groups},
+    interface::{self, select_interface, InterfaceSelector},
+    latency::{now_nanos, LatencyTracker},
+    membership::{self, is_ssm_group, Membership},
+    nack::{send_nack, NackTracker},
+    output::{OutputFormat, Record, StatsWriter},
+    packet::{self, Flags, Header, Packet},
+    pcap::PcapWriter,
+    receive::{
+        bind_socket, set_receive_buffer, BatchReceiver, Metadata, DEFAULT_BUFFER_SIZE,
+        DEFAULT_FRAMES, DEFAULT_RECEIVE_BUFFER,
     },
+    running,
+    scope::{multicast_scope, scoped_group},
+    stats::{Arrival, KernelDrops, SequenceStats, SequenceTracker},
+    status, stop, stop_on_sigint,
 };
+use nix::errno::Errno;
 
-const FRAMES: usize = 32;
-const BUFFER_SIZE: usize = 1400;
+/// A stream is identified by the group it was sent to, its sender and its
+/// stream id.
+type StreamKey = (IpAddr, SocketAddr, u16);
+
+/// Statistics collected per counter stream.
+#[derive(Default)]
+struct Stream {
+    sequence: SequenceTracker,
+    latency: LatencyTracker,
+    /// Sees every packet of the stream, so it knows which ones parity can
+    /// recover before the first parity packet arrives.
+    fec: FecDecoder,
+    /// Set with `--nack`.
+    nack: Option<NackTracker>,
+    reassembler: Reassembler,
+    /// Datagrams of this stream, including parity and duplicates.
+    packets: u64,
+    bytes: u64,
+    /// `packets` and `bytes` at the previous interval record.
+    reported: (u64, u64),
+}
+
+/// Authentication of the packets with `--psk-file`, shared by the workers.
+struct Decryption {
+    key: PresharedKey,
+    /// Keyed by the authenticated session and stream id rather than the
+    /// source address, which a replay can change. Shared, as datagrams from
+    /// another source may be steered to another worker.
+    replay: Mutex<ReplayGuard>,
+}
 
 #[derive(Parser)]
 pub struct Cli {
-    /// Group addresses (without port) to listen to.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Group addresses (without port) to listen to, either all IPv6 or all IPv4.
+    ///
+    /// Link-local and interface-local IPv6 groups are joined on the selected
+    /// interface, a scope id like in `ff02::1%2` must be its index.
     #[arg(long, default_values_t = vec!["ff14::1a".to_string()])]
     pub group_addr: Vec<String>,
 
@@ -38,139 +95,1031 @@ pub struct Cli {
     #[arg(long, default_value_t = 30000)]
     pub group_port: u16,
 
-    /// Name of interface to join on.
-    #[arg(long, default_value = "eth0")]
-    pub interface_name: String,
+    /// Interface to join on, by name, index, address or `default` for the
+    /// interface of the default route.
+    #[arg(long, visible_alias = "interface-name", default_value = "eth0")]
+    pub interface: InterfaceSelector,
+
+    /// Receive with batched `recvmmsg` calls, or with a multishot `recvmsg`
+    /// on io_uring (Linux 6.1) which waits for datagrams instead of sleeping.
+    #[arg(long, default_value_t = Backend::Recvmmsg)]
+    pub backend: Backend,
+
+    /// Number of datagrams received with one `recvmmsg` call, or buffers in
+    /// the io_uring buffer ring.
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_FRAMES).unwrap())]
+    pub frames: NonZeroUsize,
 
-    /// Throttle between `recvmmsg` syscalls.
+    /// Largest datagram in bytes, longer ones are truncated and dropped.
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_BUFFER_SIZE).unwrap())]
+    pub buffer_size: NonZeroUsize,
+
+    /// Receive on this many sockets, each served by its own thread.
+    #[arg(long, default_value_t = NonZeroUsize::MIN, conflicts_with = "capture")]
+    pub workers: NonZeroUsize,
+
+    /// How datagrams are spread over the workers: by `stream` (UDP source
+    /// port and stream id) or by `source-port` only.
+    #[arg(long, default_value_t = Steering::Stream)]
+    pub steering: Steering,
+
+    /// Pin the workers round-robin to these CPUs, e.g. `0,2,4`.
+    #[arg(long, value_delimiter = ',')]
+    pub cpus: Vec<usize>,
+
+    /// Socket receive buffer size in bytes to request from the kernel,
+    /// limited by `net.core.rmem_max`.
+    #[arg(long, default_value_t = DEFAULT_RECEIVE_BUFFER)]
+    pub rcvbuf: usize,
+
+    /// Throttle between `recvmmsg` syscalls, or longest wait for datagrams
+    /// with io_uring.
     #[arg(short, long, value_parser = parse_duration)]
     pub period: Option<Duration>,
 
-    /// Handle only received iovs that have the right length to be a counter value.
+    /// Only print the sequence numbers of valid packets.
     #[arg(short, long, action)]
     pub counter_only: bool,
+
+    /// Print statistics as human readable text, or write JSON or CSV
+    /// records to stdout and the text to stderr.
+    #[arg(long, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Interval between printing running sequence statistics.
+    #[arg(short, long, value_parser = parse_duration, default_value = "5s")]
+    pub stats_interval: Duration,
+
+    /// Join every group source-specific for these sources instead of any-source.
+    #[arg(long)]
+    pub source: Vec<IpAddr>,
+
+    /// Block these sources on any-source groups.
+    #[arg(long, conflicts_with = "source")]
+    pub block_source: Vec<IpAddr>,
+
+    /// Accept join, leave, list and stats commands from stdin (`-`) or a
+    /// Unix domain socket at this path while running.
+    #[arg(long)]
+    pub control: Option<ControlSource>,
+
+    /// Write every received datagram with its kernel receive timestamp to
+    /// this pcap file.
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+
+    /// Request lost packets again with unicast NACKs to the sender, which
+    /// needs to run with `--nack-buffer`.
+    #[arg(long, action)]
+    pub nack: bool,
+
+    /// Time between NACKs for the same missing packet.
+    #[arg(long, value_parser = parse_duration, default_value = "20ms")]
+    pub nack_interval: Duration,
+
+    /// Number of NACKs for a missing packet before giving up on it.
+    #[arg(long, default_value_t = 3)]
+    pub nack_attempts: u8,
+
+    /// Drop messages which are still incomplete after this time.
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub reassembly_timeout: Duration,
+
+    /// Bytes of incomplete messages kept per stream before dropping the
+    /// oldest ones.
+    #[arg(long, default_value_t = DEFAULT_MEMORY_LIMIT)]
+    pub reassembly_memory: usize,
+
+    /// Only accept packets encrypted and authenticated with ChaCha20-Poly1305
+    /// under the pre-shared key in this file, 32 raw bytes or 64 hex digits.
+    /// Tampered and replayed packets are dropped and counted separately.
+    #[arg(long)]
+    pub psk_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List multicast capable interfaces with their index, MTU, flags and addresses.
+    ListInterfaces,
+    /// List the groups joined on each interface according to the kernel.
+    ListGroups,
+}
+
+/// How datagrams are read from the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Recvmmsg,
+    IoUring,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "recvmmsg" => Ok(Self::Recvmmsg),
+            "io-uring" => Ok(Self::IoUring),
+            _ => Err(format!(
+                "unknown backend {s:?}, expected recvmmsg or io-uring"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recvmmsg => write!(f, "recvmmsg"),
+            Self::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+/// Receiver of the selected [`Backend`].
+enum Receiver {
+    Batch(BatchReceiver),
+    #[cfg(feature = "io-uring")]
+    Uring(Box<UringReceiver>),
+}
+
+impl Receiver {
+    #[cfg_attr(not(feature = "io-uring"), allow(unused_variables))]
+    fn new(backend: Backend, socket: RawFd, frames: usize, buffer_size: usize) -> Result<Self> {
+        match backend {
+            Backend::Recvmmsg => Ok(Self::Batch(BatchReceiver::new(frames, buffer_size))),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring => UringReceiver::new(socket, frames, buffer_size)
+                .map(|uring| Self::Uring(Box::new(uring)))
+                .context("failed to set up io_uring"),
+            #[cfg(not(feature = "io-uring"))]
+            Backend::IoUring => bail!("the receiver was built without the `io-uring` feature"),
+        }
+    }
+
+    /// Receive the available datagrams, io_uring waits up to `wait` for them.
+    #[cfg_attr(not(feature = "io-uring"), allow(unused_variables))]
+    fn recv(
+        &mut self,
+        socket: RawFd,
+        wait: Duration,
+        handle: impl FnMut(&[u8], &Metadata),
+    ) -> nix::Result<usize> {
+        match self {
+            Self::Batch(batch) => batch.recv(socket, handle),
+            #[cfg(feature = "io-uring")]
+            Self::Uring(uring) => uring.recv(wait, handle),
+        }
+    }
+
+    /// Sleep for `wait` between `recvmmsg` calls, io_uring already waited in
+    /// [`Receiver::recv`].
+    fn idle(&self, wait: Duration) {
+        match self {
+            Self::Batch(_) => std::thread::sleep(wait),
+            #[cfg(feature = "io-uring")]
+            Self::Uring(_) => {}
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
 
-    let addresses: Vec<Ipv6Addr> = args
+    match args.command {
+        Some(Command::ListInterfaces) => {
+            interface::print_interfaces();
+            return Ok(());
+        }
+        Some(Command::ListGroups) => {
+            return print_joined_groups().context("failed to read the joined groups");
+        }
+        None => {}
+    }
+    // Claim stdout before the first status line.
+    let mut stats_writer = StatsWriter::stdout(args.output);
+
+    let groups: Vec<(IpAddr, u32)> = args
         .group_addr
         .iter()
-        .map(|addr| addr.parse())
-        .collect::<std::result::Result<Vec<_>, _>>()?;
+        .map(|addr| parse_group(addr).with_context(|| format!("invalid group address {addr:?}")))
+        .collect::<Result<_>>()?;
+    let addresses: Vec<IpAddr> = groups.iter().map(|(group, _)| *group).collect();
+    let is_ipv4 = addresses
+        .first()
+        .context("no group address given")?
+        .is_ipv4();
+    if addresses.iter().any(|addr| addr.is_ipv4() != is_ipv4) {
+        bail!("group addresses must all be of the same address family");
+    }
+    let interface = select_interface(&args.interface)?;
+    for (group, scope_id) in groups {
+        let group = SocketAddr::V6(match group {
+            IpAddr::V6(group) => SocketAddrV6::new(group, args.group_port, 0, scope_id),
+            IpAddr::V4(_) => continue,
+        });
+        scoped_group(group, interface.index)
+            .with_context(|| format!("can not join {group} on {}", interface.name))?;
+    }
     let throttle = args.period.unwrap_or(Duration::from_millis(100));
+    let decryption = args
+        .psk_file
+        .as_deref()
+        .map(PresharedKey::from_file)
+        .transpose()
+        .context("failed to read the pre-shared key")?
+        .map(|key| Decryption {
+            key,
+            replay: Mutex::default(),
+        });
 
-    let socket = socket(
-        AddressFamily::Inet6,
-        SockType::Datagram,
-        SockFlag::SOCK_NONBLOCK,
-        None,
-    )
-    .context("failed to open socket")?;
-
-    setsockopt(socket, sockopt::ReceiveTimestamp, &true)
-        .context("failed to enable receive timestamps")?;
-    setsockopt(socket, sockopt::ReuseAddr, &true).context("failed to enable reuseaddr")?;
-    setsockopt(socket, sockopt::RxqOvfl, &1)
-        .context("failed to enable receive queue overflow tracking")?;
+    let family = address_family(&addresses[0]);
+    let workers = args.workers.get();
+    let sockets = (0..workers as u32)
+        .map(|worker| match workers {
+            1 => bind_socket(family, args.group_port),
+            _ => bind_worker_socket(
+                family,
+                args.group_port,
+                args.steering,
+                workers as u32,
+                worker,
+            ),
+        })
+        .collect::<nix::Result<Vec<_>>>()
+        .context("failed to open socket")?;
     // The receive buffer gets some overhead size by the kernel but may be
     // limited by parameters.
-    setsockopt(socket, sockopt::RcvBuf, &512000).context("failed to set receive buffer size")?;
-    let bind_addr: SockaddrIn6 =
-        SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, args.group_port, 0, 0).into();
-    bind(socket, &bind_addr)?;
+    let mut receive_buffer = None;
+    for &socket in &sockets {
+        receive_buffer = Some(
+            set_receive_buffer(socket, args.rcvbuf).context("failed to set receive buffer size")?,
+        );
+    }
+    let receive_buffer = receive_buffer.expect("at least one socket");
+    status!("Receive buffer: {receive_buffer}");
+    if receive_buffer.is_clamped() {
+        status!(
+            "Warning: the receive buffer was clamped, raise the limit with \
+             `sysctl -w net.core.rmem_max={}`",
+            args.rcvbuf
+        );
+    }
+    if workers > 1 {
+        status!(
+            "Spreading datagrams over {workers} workers by {}",
+            args.steering
+        );
+    }
 
-    let interface =
-        get_interface_by_name(&args.interface_name).with_context(|| "failed to find interface")?;
+    let mut memberships = Vec::new();
     for group_addr in addresses {
-        let join_req = get_ipv6_join_request(&group_addr, interface.index)?;
-        setsockopt(socket, sockopt::Ipv6AddMembership, &join_req)
-            .context("failed to join multicast group")?;
+        if !args.source.is_empty() {
+            for source in &args.source {
+                let membership = Membership::source_specific(group_addr, *source, interface.index);
+                join_all(&sockets, &membership)
+                    .with_context(|| format!("failed to join {group_addr} for source {source}"))?;
+                memberships.push(membership);
+            }
+            continue;
+        }
+
+        if is_ssm_group(&group_addr) {
+            status!("Warning: {group_addr} is a source-specific group, use --source to join it");
+        }
+        let membership = Membership::any_source(group_addr, interface.index);
+        join_all(&sockets, &membership).context("failed to join multicast group")?;
+        memberships.push(membership);
+
+        for source in &args.block_source {
+            for &socket in &sockets {
+                membership::block_source(socket, &group_addr, source, interface.index)
+                    .with_context(|| format!("failed to block source {source} on {group_addr}"))?;
+            }
+        }
+    }
+    verify_memberships(&memberships);
+
+    let control = args
+        .control
+        .as_ref()
+        .map(ControlChannel::open)
+        .transpose()
+        .context("failed to open control channel")?;
+    stop_on_sigint().context("failed to install SIGINT handler")?;
+
+    let mut capture = match &args.capture {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            Some(PcapWriter::new(BufWriter::new(file)).context("failed to write capture")?)
+        }
+        None => None,
+    };
+
+    // Missing packets have to be requested again in time.
+    let wait = match args.nack {
+        true => throttle.min(args.nack_interval),
+        false => throttle,
+    };
+    let states: Vec<Mutex<SocketState>> = sockets.iter().map(|_| Mutex::default()).collect();
+    let started = Instant::now();
+    let mut last_report = started;
+    let args = &args;
+    let decryption = decryption.as_ref();
+
+    thread::scope(|scope| -> Result<()> {
+        // A single socket is served by the main thread, between the
+        // statistics and control commands.
+        let mut receiver = None;
+        let mut handles = Vec::new();
+        if workers == 1 {
+            if let Some(&cpu) = args.cpus.first() {
+                pin_thread(cpu).with_context(|| format!("failed to pin receiver to CPU {cpu}"))?;
+            }
+            receiver = Some(Receiver::new(
+                args.backend,
+                sockets[0],
+                args.frames.get(),
+                args.buffer_size.get(),
+            )?);
+        } else {
+            for (idx, (&socket, state)) in sockets.iter().zip(&states).enumerate() {
+                let cpu = (!args.cpus.is_empty()).then(|| args.cpus[idx % args.cpus.len()]);
+                handles.push(
+                    scope.spawn(move || run_worker(args, decryption, socket, cpu, wait, state)),
+                );
+            }
+        }
+
+        // Stop all workers once one of them failed.
+        while running() && !handles.iter().any(|handle| handle.is_finished()) {
+            if let Some(receiver) = &mut receiver {
+                receive(
+                    receiver,
+                    sockets[0],
+                    wait,
+                    args,
+                    decryption,
+                    &states[0],
+                    &mut capture,
+                );
+            }
+
+            if last_report.elapsed() >= args.stats_interval {
+                let mut guards: Vec<_> = states.iter().map(lock).collect();
+                let mut merged = merge(&mut guards);
+                let now = Instant::now();
+                for stream in merged.streams.values_mut() {
+                    stream.reassembler.expire(now);
+                }
+                print_stats(&merged, false);
+                if let Some(writer) = &mut stats_writer {
+                    write_records(writer, &mut merged, started, Some(last_report.elapsed()))
+                        .context("failed to write statistics")?;
+                }
+                last_report = Instant::now();
+            }
+
+            // Memberships change between batches, the statistics are kept.
+            while let Some(request) = control.as_ref().and_then(ControlChannel::try_recv) {
+                let reply = match request.command {
+                    ControlCommand::Join { group, source } => {
+                        let membership = Membership {
+                            group,
+                            source,
+                            interface_index: interface.index,
+                        };
+                        join_at_runtime(&sockets, &mut memberships, membership, is_ipv4)
+                    }
+                    ControlCommand::Leave { group, source } => {
+                        let membership = Membership {
+                            group,
+                            source,
+                            interface_index: interface.index,
+                        };
+                        leave_at_runtime(&sockets, &mut memberships, &membership)
+                    }
+                    ControlCommand::List => {
+                        let list: Vec<_> = memberships.iter().map(ToString::to_string).collect();
+                        format!("ok: {}", list.join(", "))
+                    }
+                    ControlCommand::Stats => {
+                        let mut guards: Vec<_> = states.iter().map(lock).collect();
+                        print_stats(&merge(&mut guards), false);
+                        "ok".to_owned()
+                    }
+                };
+                status!("Control: {reply}");
+                request.reply(reply);
+            }
+
+            match &receiver {
+                Some(receiver) => receiver.idle(wait),
+                None => thread::sleep(wait),
+            }
+        }
+
+        stop();
+        for handle in handles {
+            handle.join().expect("worker panicked")?;
+        }
+        Ok(())
+    })?;
+
+    if let Some(mut writer) = capture {
+        writer.flush().context("failed to write capture")?;
+    }
+
+    status!("Final statistics:");
+    let mut guards: Vec<_> = states.iter().map(lock).collect();
+    let mut merged = merge(&mut guards);
+    for stream in merged.streams.values_mut() {
+        stream.fec.finish();
+    }
+    print_stats(&merged, true);
+    if let Some(writer) = &mut stats_writer {
+        write_records(writer, &mut merged, started, None).context("failed to write statistics")?;
     }
 
-    let cmsg_buffer = cmsg_space!(TimeVal, u32);
-    let mut multi_headers = MultiHeaders::<SockaddrIn>::preallocate(FRAMES, Some(cmsg_buffer));
+    Ok(())
+}
+
+/// Streams and counters of one socket. With `--workers`, the worker thread of
+/// the socket updates them and the main thread reads them for the statistics.
+#[derive(Default)]
+struct SocketState {
+    streams: BTreeMap<StreamKey, Stream>,
+    /// Including the datagrams rejected by the steering filter.
+    kernel_drops: KernelDrops,
+    /// All datagrams received on the socket.
+    datagrams: u64,
+    truncated: u64,
+    /// Datagrams failing authentication with `--psk-file`.
+    auth_failures: u64,
+    /// Authenticated packets which were received before.
+    replayed: u64,
+}
+
+/// The state of all sockets, merged for the statistics. The steering keeps
+/// the streams of the sockets disjoint.
+#[derive(Default)]
+struct Merged<'a> {
+    streams: BTreeMap<StreamKey, &'a mut Stream>,
+    kernel_drops: KernelDrops,
+    truncated: u64,
+    auth_failures: u64,
+    replayed: u64,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("worker panicked")
+}
 
-    let mut buffers = [[0u8; BUFFER_SIZE]; FRAMES];
-    let msgs: Vec<_> = buffers
-        .iter_mut()
-        .map(|buf| [IoSliceMut::new(&mut buf[..])])
-        .collect();
+fn merge<'a>(guards: &'a mut [MutexGuard<'_, SocketState>]) -> Merged<'a> {
+    let mut merged = Merged::default();
+    let sockets = guards.len() as u64;
+    let (mut drop_counters, mut datagrams) = (0, 0);
+    for state in guards.iter_mut() {
+        let state = &mut **state;
+        merged
+            .streams
+            .extend(state.streams.iter_mut().map(|(key, stream)| (*key, stream)));
+        drop_counters += state.kernel_drops.total();
+        datagrams += state.datagrams;
+        merged.truncated += state.truncated;
+        merged.auth_failures += state.auth_failures;
+        merged.replayed += state.replayed;
+    }
+    let drops = buffer_drops(drop_counters, datagrams, sockets);
+    merged.kernel_drops = KernelDrops::from_total(drops);
+    merged
+}
 
-    loop {
-        let _ = match recvmmsg(
+/// Receive on `socket` until stopped.
+fn run_worker(
+    args: &Cli,
+    decryption: Option<&Decryption>,
+    socket: RawFd,
+    cpu: Option<usize>,
+    wait: Duration,
+    state: &Mutex<SocketState>,
+) -> Result<()> {
+    if let Some(cpu) = cpu {
+        pin_thread(cpu).with_context(|| format!("failed to pin worker to CPU {cpu}"))?;
+    }
+    // An io_uring has to be used by the thread which created it.
+    let mut receiver = Receiver::new(
+        args.backend,
+        socket,
+        args.frames.get(),
+        args.buffer_size.get(),
+    )?;
+    while running() {
+        receive(
+            &mut receiver,
             socket,
-            &mut multi_headers,
-            msgs.iter(),
-            MsgFlags::empty(),
-            None,
-        ) {
-            Ok(multi_results) => {
-                let mut frames = 0;
-
-                for recv_msg in multi_results {
-                    frames += 1;
-
-                    for iov in recv_msg.iovs() {
-                        decode_payload(iov, args.counter_only);
+            wait,
+            args,
+            decryption,
+            state,
+            &mut None,
+        );
+        receiver.idle(wait);
+    }
+    Ok(())
+}
+
+/// Receive the available datagrams on `socket` and send the NACKs which are
+/// due. With `decryption`, only authenticated packets are accepted once.
+fn receive(
+    receiver: &mut Receiver,
+    socket: RawFd,
+    wait: Duration,
+    args: &Cli,
+    decryption: Option<&Decryption>,
+    state: &Mutex<SocketState>,
+    capture: &mut Option<PcapWriter<BufWriter<File>>>,
+) {
+    let mut drop_counter = None;
+    let mut plaintext = Vec::new();
+    // Locked with the first datagram, so the statistics and control
+    // commands of the main thread do not wait while io_uring waits.
+    let mut guard = None;
+    let result = receiver.recv(socket, wait, |payload, meta| {
+        let SocketState {
+            streams,
+            datagrams,
+            truncated,
+            auth_failures,
+            replayed,
+            ..
+        } = &mut **guard.get_or_insert_with(|| lock(state));
+        *datagrams += 1;
+        drop_counter = meta.drop_counter.or(drop_counter);
+
+        if let Some(writer) = capture {
+            if let Err(e) = capture_datagram(writer, payload, meta, args.group_port) {
+                status!("Stopping capture: {e}");
+                *capture = None;
+            }
+        }
+
+        if let Some(len) = meta.truncated_from {
+            *truncated += 1;
+            status!(
+                "Dropping datagram of {len} bytes larger than the buffer size {}",
+                args.buffer_size
+            );
+            return;
+        }
+
+        let Some(packet) = decode_payload(payload, args.counter_only) else {
+            return;
+        };
+        // The session id is part of the sealed payload.
+        let (packet, session) = match decryption {
+            Some(decryption) => match decryption.key.open(payload, &packet, &mut plaintext) {
+                Ok(opened) => (opened, session_id(packet.payload)),
+                Err(e) => {
+                    *auth_failures += 1;
+                    if !args.counter_only {
+                        status!(
+                            "Dropping datagram with length {} failing authentication: {e}",
+                            payload.len()
+                        );
                     }
+                    return;
                 }
+            },
+            None if packet.header.flags.contains(Flags::ENCRYPTED) => {
+                if !args.counter_only {
+                    status!("Dropping encrypted datagram, decrypting needs --psk-file");
+                }
+                return;
+            }
+            None => (packet, None),
+        };
 
-                frames
+        if let Some((group, source)) = meta.group.zip(meta.source) {
+            let key = (group, source, packet.header.stream_id);
+            if let Some((decryption, session)) = decryption.zip(session) {
+                if !lock(&decryption.replay).accept(&packet.header, session) {
+                    reject_replay(key, &packet.header, replayed);
+                    return;
+                }
+            }
+            let stream = streams.entry(key).or_insert_with(|| Stream {
+                nack: args
+                    .nack
+                    .then(|| NackTracker::new(args.nack_interval, args.nack_attempts)),
+                reassembler: Reassembler::new(args.reassembly_timeout, args.reassembly_memory),
+                ..Default::default()
+            });
+            stream.packets += 1;
+            stream.bytes += payload.len() as u64;
+            track_packet(stream, key, &packet, meta.received_ns);
+        }
+    });
+    let mut state = guard.unwrap_or_else(|| lock(state));
+    match result {
+        Ok(_) => {
+            if let Some(counter) = drop_counter {
+                let kernel_drops = &mut state.kernel_drops;
+                let dropped = kernel_drops.update(counter);
+                // With several workers, the counter includes the datagrams
+                // of the other workers.
+                if dropped > 0 && args.workers.get() == 1 {
+                    status!(
+                        "Kernel dropped {dropped} datagrams (total {}), receive buffer overflow",
+                        kernel_drops.total()
+                    );
+                }
             }
+        }
+        Err(Errno::EAGAIN) => {}
+        Err(e) => status!("Error in {}: {e}", args.backend),
+    }
 
-            Err(Errno::EAGAIN) => 0,
+    if args.nack {
+        send_nacks(socket, &mut state.streams);
+    }
+}
 
-            Err(e) => {
-                println!("Error in recvmmsg: {e}");
-                0
-            }
+/// Write one record per stream, followed by one with the counters of all
+/// sockets, which is written even without streams. The stream fields are
+/// empty in the latter and the socket counters in the former, so summing
+/// over the records counts everything once. The rates are over the
+/// `interval` since the previous record, or over the whole run for the
+/// summary without one.
+fn write_records(
+    writer: &mut StatsWriter<io::Stdout>,
+    merged: &mut Merged,
+    started: Instant,
+    interval: Option<Duration>,
+) -> io::Result<()> {
+    let elapsed = started.elapsed();
+    let seconds = interval.unwrap_or(elapsed).as_secs_f64();
+    let record = || {
+        Record::new()
+            .field(
+                "record",
+                match interval {
+                    Some(_) => "interval",
+                    None => "summary",
+                },
+            )
+            .field("timestamp_ns", now_nanos())
+            .field("elapsed_s", elapsed.as_secs_f64())
+    };
+
+    for (key, stream) in merged.streams.iter_mut() {
+        let (packets, bytes) = match interval {
+            Some(_) => (
+                stream.packets - stream.reported.0,
+                stream.bytes - stream.reported.1,
+            ),
+            None => (stream.packets, stream.bytes),
         };
+        stream.reported = (stream.packets, stream.bytes);
+        let rates = (packets as f64 / seconds, (bytes * 8) as f64 / seconds);
+        let record = stream_fields(record(), Some((key, stream, rates)));
+        writer.write(&socket_fields(record, None))?;
+    }
+    let record = stream_fields(record(), None);
+    writer.write(&socket_fields(record, Some(merged)))
+}
+
+/// Append the fields of a stream with its packet and bit rate, or empty ones.
+fn stream_fields(record: Record, stream: Option<(&StreamKey, &Stream, (f64, f64))>) -> Record {
+    let key = stream.map(|(key, _, _)| key);
+    let rates = stream.map(|(_, _, rates)| rates);
+    let stream = stream.map(|(_, stream, _)| stream);
+    let stats = stream.map(|stream| stream.sequence.stats());
+    // Latency fields are empty without timestamped packets.
+    let latency = stream
+        .map(|stream| &stream.latency)
+        .filter(|latency| latency.count() > 0);
+    // The bucket bounds are coarse, keep them within the measured range.
+    let percentile = |quantile| {
+        latency.and_then(|latency| {
+            latency
+                .histogram()
+                .percentile(quantile)
+                .map(|bound| (bound.as_nanos() as i64).clamp(latency.min_ns(), latency.max_ns()))
+        })
+    };
+
+    record
+        .field("group", key.map(|(group, _, _)| group.to_string()))
+        .field("source", key.map(|(_, source, _)| source.to_string()))
+        .field("stream_id", key.map(|&(_, _, stream_id)| stream_id))
+        .field("packets", stream.map(|stream| stream.packets))
+        .field("bytes", stream.map(|stream| stream.bytes))
+        .field("packet_rate", rates.map(|(packet_rate, _)| packet_rate))
+        .field("bitrate", rates.map(|(_, bitrate)| bitrate))
+        .field("received", stats.map(|stats| stats.received))
+        .field("lost", stats.map(|stats| stats.lost))
+        .field("loss_rate", stats.map(|stats| stats.loss_rate()))
+        .field("duplicates", stats.map(|stats| stats.duplicates))
+        .field("reordered", stats.map(|stats| stats.reordered))
+        .field("recovered", stats.map(|stats| stats.recovered))
+        .field("latency_min_ns", latency.map(LatencyTracker::min_ns))
+        .field("latency_mean_ns", latency.map(LatencyTracker::mean_ns))
+        .field("latency_p50_ns", percentile(0.5))
+        .field("latency_p90_ns", percentile(0.9))
+        .field("latency_p99_ns", percentile(0.99))
+        .field("latency_max_ns", latency.map(LatencyTracker::max_ns))
+        .field(
+            "jitter_ns",
+            latency.map(|latency| latency.jitter().as_nanos() as u64),
+        )
+}
+
+/// Append the counters of all sockets, or empty ones.
+fn socket_fields(record: Record, merged: Option<&Merged>) -> Record {
+    record
+        .field(
+            "kernel_drops",
+            merged.map(|merged| merged.kernel_drops.total()),
+        )
+        .field("truncated", merged.map(|merged| merged.truncated))
+        .field("auth_failures", merged.map(|merged| merged.auth_failures))
+        .field("replayed", merged.map(|merged| merged.replayed))
+}
+
+fn capture_datagram(
+    writer: &mut PcapWriter<BufWriter<File>>,
+    payload: &[u8],
+    meta: &Metadata,
+    group_port: u16,
+) -> std::io::Result<()> {
+    let Some((group, source)) = meta.group.zip(meta.source) else {
+        return Ok(());
+    };
+    let received_ns = meta.received_ns.unwrap_or_else(|| now_nanos() as i64);
+    writer.write_datagram(
+        received_ns,
+        source,
+        SocketAddr::new(group, group_port),
+        payload,
+    )
+}
 
-        std::thread::sleep(throttle);
+fn join_all(sockets: &[RawFd], membership: &Membership) -> nix::Result<()> {
+    sockets
+        .iter()
+        .try_for_each(|&socket| membership::join(socket, membership))
+}
+
+/// Warn about memberships which the kernel does not list as joined.
+fn verify_memberships(memberships: &[Membership]) {
+    match joined_groups() {
+        Ok(joined) => {
+            for membership in missing(memberships, &joined) {
+                status!("Warning: {membership} is missing from the groups joined by the kernel");
+            }
+        }
+        Err(e) => status!("Warning: failed to read the joined groups: {e}"),
     }
 }
 
-fn decode_payload(mut payload: &[u8], counter_only: bool) {
-    match counter_only {
-        true => {
-            if payload.len() == 4 {
-                let counter = payload.get_u32();
-                println!("Counter: {counter}");
+/// Parse a group address with an optional scope id, e.g. `ff02::1%2`.
+fn parse_group(addr: &str) -> Result<(IpAddr, u32)> {
+    let (group, scope_id) = match addr.split_once('%') {
+        Some((group, scope_id)) => (group, scope_id.parse().context("invalid scope id")?),
+        None => (addr, 0),
+    };
+    let group: IpAddr = group.parse()?;
+    multicast_scope(&group)?;
+    if scope_id != 0 && group.is_ipv4() {
+        bail!("IPv4 groups have no scope id");
+    }
+    Ok((group, scope_id))
+}
+
+fn join_at_runtime(
+    sockets: &[RawFd],
+    memberships: &mut Vec<Membership>,
+    membership: Membership,
+    is_ipv4: bool,
+) -> String {
+    if let Err(e) = multicast_scope(&membership.group) {
+        return format!("error: {e}");
+    }
+    if membership.group.is_ipv4() != is_ipv4 {
+        return format!(
+            "error: {} is not of the socket's address family",
+            membership.group
+        );
+    }
+    if memberships.contains(&membership) {
+        return format!("error: already joined {membership}");
+    }
+    match join_all(sockets, &membership) {
+        Ok(()) => {
+            memberships.push(membership);
+            format!("ok: joined {membership}")
+        }
+        Err(e) => format!("error: failed to join {membership}: {e}"),
+    }
+}
+
+fn leave_at_runtime(
+    sockets: &[RawFd],
+    memberships: &mut Vec<Membership>,
+    membership: &Membership,
+) -> String {
+    let Some(position) = memberships.iter().position(|joined| joined == membership) else {
+        return format!("error: not joined {membership}");
+    };
+    let left = sockets
+        .iter()
+        .try_for_each(|&socket| membership::leave(socket, membership));
+    match left {
+        Ok(()) => {
+            memberships.remove(position);
+            format!("ok: left {membership}")
+        }
+        Err(e) => format!("error: failed to leave {membership}: {e}"),
+    }
+}
+
+/// Decode a packet from a received iov, reporting malformed datagrams.
+fn decode_payload(payload: &[u8], counter_only: bool) -> Option<Packet<'_>> {
+    match packet::decode(payload) {
+        Ok(packet) => {
+            if counter_only {
+                status!("Counter: {}", packet.header.sequence);
+            }
+            Some(packet)
+        }
+        Err(e) => {
+            if !counter_only {
+                status!("Dropping invalid iov with length {}: {e}", payload.len());
             }
+            None
         }
-        false => {
-            println!("Received iov with length {}", payload.len());
+    }
+}
+
+/// Send the NACKs which are due to the sources of the streams.
+fn send_nacks(socket: RawFd, streams: &mut BTreeMap<StreamKey, Stream>) {
+    let now = Instant::now();
+    for ((group, source, stream_id), stream) in streams.iter_mut() {
+        let Some(nack) = stream
+            .nack
+            .as_mut()
+            .and_then(|nack| nack.poll(*stream_id, now))
+        else {
+            continue;
+        };
+        if let Err(e) = send_nack(socket, &nack, *source) {
+            status!("[{group} from {source} #{stream_id}] failed to send NACK: {e}");
         }
     }
 }
 
-fn get_ipv6_join_request(group: &Ipv6Addr, interface_index: u32) -> Result<Ipv6MembershipRequest> {
-    // nix does not provide a public method to create a
-    // `Ipv6MembershipRequest` with a specific interface index. See:
-    // https://github.com/nix-rust/nix/issues/323
-    // To work around it, we create the inner type and transmute it to the
-    // required type. Static assertions ensure the safety.
+fn track_packet(stream: &mut Stream, key: StreamKey, packet: &Packet, received_ns: Option<i64>) {
+    let (group, source, stream_id) = key;
+    let name = format!("{group} from {source} #{stream_id}");
 
-    // Depending on the target platform, this is a `u32` or `i32`.
-    #[cfg(target_os = "android")]
-    let interface_index = interface_index as i32;
+    if packet.header.flags.contains(Flags::PARITY) {
+        for recovered in stream.fec.push(packet) {
+            track_recovered(stream, &name, &recovered);
+        }
+        return;
+    }
 
-    static_assertions::assert_eq_size!(Ipv6Addr, nix::libc::in6_addr);
-    let join_request = nix::libc::ipv6_mreq {
-        // SAFETY:
-        // Safe as long as `std::net::Ipv6Addr` and `libc::in6_addr` are the
-        // same size as asserted above.
-        ipv6mr_multiaddr: unsafe { std::mem::transmute(*group) },
-        ipv6mr_interface: interface_index,
-    };
+    let counter = packet.header.sequence;
+    if packet.header.flags.contains(Flags::RETRANSMIT) {
+        // The timestamp of the original send would skew the latency.
+        match stream.sequence.recover(counter) {
+            true => status!("[{name}] retransmitted {counter}"),
+            false => status!("[{name}] unneeded retransmission {counter}"),
+        }
+    } else {
+        let sent_ns = Some(packet.header.timestamp_ns).filter(|&sent_ns| sent_ns != 0);
+        if let Some((sent_ns, received_ns)) = sent_ns.zip(received_ns) {
+            stream.latency.record(sent_ns, received_ns);
+        }
+
+        match stream.sequence.record(counter) {
+            Arrival::First => status!("New stream to {name} at counter {counter}"),
+            Arrival::Gap(gap) => {
+                status!("[{name}] {gap} lost before {counter}");
+                if let Some(nack) = &mut stream.nack {
+                    nack.on_gap(counter, gap);
+                }
+            }
+            Arrival::Duplicate => status!("[{name}] duplicate {counter}"),
+            Arrival::Reordered { depth } => status!("[{name}] reordered {counter} by {depth}"),
+            Arrival::Restart => status!("[{name}] restarted at {counter}"),
+            Arrival::InOrder => {}
+        }
+    }
+    if let Some(nack) = &mut stream.nack {
+        nack.on_received(counter);
+    }
+    reassemble(stream, &name, &packet.header, packet.payload);
 
-    // SAFETY:
-    // Safe as long as `Ipv6MembershipRequest` is just a wrapper of
-    // `libc::ipv6_mreq` as asserted.
-    static_assertions::assert_eq_size!(Ipv6MembershipRequest, nix::libc::ipv6_mreq);
-    let join_request: Ipv6MembershipRequest = unsafe { std::mem::transmute(join_request) };
+    for recovered in stream.fec.push(packet) {
+        track_recovered(stream, &name, &recovered);
+    }
+}
+
+/// Report an authenticated packet received before. Retransmissions of
+/// received packets are expected and not counted as replays.
+fn reject_replay(key: StreamKey, header: &Header, replayed: &mut u64) {
+    let (group, source, stream_id) = key;
+    let name = format!("{group} from {source} #{stream_id}");
+    let counter = header.sequence;
+    if header.flags.contains(Flags::RETRANSMIT) {
+        status!("[{name}] unneeded retransmission {counter}");
+    } else {
+        *replayed += 1;
+        status!("[{name}] dropping replayed {counter}");
+    }
+}
+
+fn track_recovered(stream: &mut Stream, name: &str, recovered: &Recovered) {
+    let counter = recovered.header.sequence;
+    if stream.sequence.recover(counter) {
+        status!("[{name}] recovered {counter}");
+    }
+    if let Some(nack) = &mut stream.nack {
+        nack.on_received(counter);
+    }
+    reassemble(stream, name, &recovered.header, &recovered.payload);
+}
+
+/// Place a fragment into its message, the complete messages are only
+/// counted.
+fn reassemble(stream: &mut Stream, name: &str, header: &Header, payload: &[u8]) {
+    if !header.flags.contains(Flags::FRAGMENT) {
+        return;
+    }
+    if let Err(e) = stream.reassembler.push(payload, Instant::now()) {
+        status!("[{name}] dropping fragment {}: {e}", header.sequence);
+    }
+}
 
-    Ok(join_request)
+fn print_stats(merged: &Merged, with_histogram: bool) {
+    let streams = &merged.streams;
+    for ((group, source, stream_id), stream) in streams {
+        let name = format!("{group} from {source} #{stream_id}");
+        status!("[{name}] {}", stream.sequence.stats());
+        if stream.fec.stats().parity > 0 {
+            status!("[{name}] {}", stream.fec.stats());
+        }
+        if let Some(nack) = &stream.nack {
+            status!("[{name}] {}", nack.stats());
+        }
+        let reassembly = stream.reassembler.stats();
+        if reassembly.fragments > 0 || reassembly.rejected > 0 {
+            status!("[{name}] {reassembly}");
+        }
+        if stream.latency.count() > 0 {
+            status!("[{name}] {}", stream.latency);
+            if with_histogram {
+                status!("{}", stream.latency.histogram().to_string().trim_end());
+            }
+        }
+    }
+
+    // Aggregate over all sources and streams of a group.
+    let mut groups: BTreeMap<IpAddr, (usize, SequenceStats)> = BTreeMap::new();
+    for ((group, _, _), stream) in streams {
+        let (count, stats) = groups.entry(*group).or_default();
+        *count += 1;
+        stats.merge(&stream.sequence.stats());
+    }
+    if streams.len() > groups.len() {
+        for (group, (count, stats)) in &groups {
+            status!("[{group}, {count} streams] {stats}");
+        }
+    }
+
+    let sequence_lost = streams
+        .values()
+        .map(|stream| stream.sequence.stats().lost)
+        .sum();
+    let (buffer_lost, network_lost) = merged.kernel_drops.attribute(sequence_lost);
+    status!(
+        "Lost {sequence_lost}: {buffer_lost} in socket buffer, {network_lost} on network \
+         (kernel drops total {})",
+        merged.kernel_drops.total()
+    );
+    if merged.truncated > 0 {
+        status!(
+            "Dropped {} datagrams larger than the buffer size",
+            merged.truncated
+        );
+    }
+    if merged.auth_failures > 0 || merged.replayed > 0 {
+        status!(
+            "Rejected {} datagrams failing authentication, {} replayed",
+            merged.auth_failures,
+            merged.replayed
+        );
+    }
 }
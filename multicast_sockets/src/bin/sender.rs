@@ -1,100 +1,851 @@
 use std::{
-    io::IoSlice,
-    net::{IpAddr, Ipv6Addr, SocketAddrV6},
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
     num::Wrapping,
-    time::Duration,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 use humantime::parse_duration;
-use multicast_sockets::get_interface_by_name;
-use nix::{
-    libc::{in6_addr, in6_pktinfo},
-    sys::socket::{
-        bind, sendmsg, socket, ControlMessage, MsgFlags, SockFlag, SockType, SockaddrIn6,
-    },
+use multicast_sockets::{
+    address_family,
+    crypto::{PresharedKey, Sealer},
+    fec::{FecConfig, FecEncoder},
+    fragment::{max_chunk_len, Fragmenter, FRAGMENT_HEADER_LEN},
+    interface::{self, select_interface, InterfaceSelector},
+    latency::now_nanos,
+    nack::{recv_nack, Nack, RetransmitBuffer},
+    output::{OutputFormat, Record, StatsWriter},
+    pacing::{parse_bitrate, parse_rate, TokenBucket},
+    packet::{self, Flags, Header},
+    pcap::PcapReader,
+    running,
+    scope::{multicast_scope, scoped_group},
+    send::{self, bind_socket, BatchSender, PacketInfo},
+    status, stop_on_sigint,
 };
+use nix::{errno::Errno, sys::socket::AddressFamily};
+use pnet_datalink::NetworkInterface;
 
 #[derive(Parser)]
 pub struct Cli {
-    /// Source port.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Source port, with `--separate-sockets` the first of consecutive ports.
     #[arg(long, default_value_t = 20202)]
     pub src_port: u16,
 
-    /// Name of interface to use.
-    #[arg(short, long, default_value = "eth0")]
-    pub interface_name: String,
+    /// Interface to use, by name, index, address or `default` for the
+    /// interface of the default route.
+    #[arg(short, long, visible_alias = "interface-name", default_value = "eth0")]
+    pub interface: InterfaceSelector,
+
+    /// Target group address, IPv6 (`[ff14::1a]:30000`) or IPv4 (`239.1.2.3:30000`).
+    ///
+    /// Link-local and interface-local IPv6 groups are scoped to the
+    /// interface, a scope id like in `[ff02::1%2]:30000` must be its index.
+    ///
+    /// May be given several times. Each target can override the global
+    /// profile with comma separated options, e.g.
+    /// `[ff14::1b]:30000,rate=1000,size=512,burst=8,stream=3`.
+    #[arg(short, long, default_values_t = vec![TargetSpec::default()])]
+    pub target_group: Vec<TargetSpec>,
 
-    /// Target group address.
-    #[arg(short, long, default_value = "[ff14::1a]:30000")]
-    pub target_group: String,
+    /// Open one socket per target group instead of sharing one.
+    #[arg(long, action)]
+    pub separate_sockets: bool,
 
     /// Send period.
-    #[arg(short, long, value_parser = parse_duration)]
+    #[arg(short, long, value_parser = parse_period)]
     pub period: Option<Duration>,
+
+    /// Stream id to tag the packets with, incremented for every further target.
+    #[arg(long, default_value_t = 0)]
+    pub stream_id: u16,
+
+    /// Append a CRC-32 to every packet.
+    #[arg(long, action)]
+    pub crc: bool,
+
+    /// Target packet rate in packets per second, overrides `--period`.
+    #[arg(short, long, value_parser = parse_rate, conflicts_with = "bitrate")]
+    pub rate: Option<f64>,
+
+    /// Target bit rate of the datagrams, e.g. `10M`, overrides `--period`.
+    #[arg(short, long, value_parser = parse_bitrate)]
+    pub bitrate: Option<f64>,
+
+    /// Application payload bytes after the packet header.
+    #[arg(long, default_value_t = 0)]
+    pub payload_size: u16,
+
+    /// Send messages of this many bytes instead of single packets, split
+    /// into fragments which fit the MTU. Rates and counts are per message.
+    #[arg(long, conflicts_with = "payload_size")]
+    pub message_size: Option<u32>,
+
+    /// MTU to fit the fragments of `--message-size` into, defaults to the
+    /// MTU of the interface.
+    #[arg(long, requires = "message_size")]
+    pub mtu: Option<u32>,
+
+    /// Maximum number of packets sent back-to-back with one `sendmmsg` call.
+    #[arg(long, default_value_t = 32)]
+    pub burst: u32,
+
+    /// Stop after sending this many packets per target.
+    #[arg(long)]
+    pub count: Option<u64>,
+
+    /// Stop after this duration.
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Multicast TTL (IPv4) or hop limit (IPv6), limits the forwarding scope.
+    #[arg(long, visible_alias = "ttl")]
+    pub hops: Option<u8>,
+
+    /// Do not loop sent datagrams back to receivers on this host.
+    #[arg(long, action)]
+    pub no_loop: bool,
+
+    /// DSCP to mark the datagrams with, e.g. 46 for expedited forwarding.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..64), conflicts_with = "tclass")]
+    pub dscp: Option<u8>,
+
+    /// Raw IPv6 traffic class or IPv4 TOS byte, including the ECN bits.
+    #[arg(long)]
+    pub tclass: Option<u8>,
+
+    /// Send the UDP payloads of this pcap file to the target group with the
+    /// original inter-packet timing instead of generated packets.
+    #[arg(long, conflicts_with_all = ["period", "rate", "bitrate", "count"])]
+    pub replay: Option<PathBuf>,
+
+    /// Send XOR parity over blocks of `N` packets, interleaved `D` deep with
+    /// `NxD`, in addition to the requested rate.
+    #[arg(long)]
+    pub fec: Option<FecConfig>,
+
+    /// Keep the last `N` packets of every target and send them again when a
+    /// receiver NACKs them. With `--count`, NACKs are still served for
+    /// another second after the last packet.
+    #[arg(long, conflicts_with = "replay")]
+    pub nack_buffer: Option<usize>,
+
+    /// Encrypt and authenticate the payloads with ChaCha20-Poly1305 under
+    /// the pre-shared key in this file, 32 raw bytes or 64 hex digits.
+    #[arg(long, conflicts_with = "replay")]
+    pub psk_file: Option<PathBuf>,
+
+    /// Print the report as human readable text, or write JSON or CSV
+    /// records to stdout and the text to stderr.
+    #[arg(long, default_value_t = OutputFormat::Text, conflicts_with = "replay")]
+    pub output: OutputFormat,
+
+    /// Interval between records with `--output json` or `csv`.
+    #[arg(short, long, value_parser = parse_duration, default_value = "5s")]
+    pub stats_interval: Duration,
+}
+
+/// How long NACKs are served after the last packet of a finite stream.
+const NACK_LINGER: Duration = Duration::from_secs(1);
+/// Longest sleep between checks for NACKs.
+const NACK_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Target group with optional overrides of the global send profile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetSpec {
+    pub group: SocketAddr,
+    pub rate: Option<f64>,
+    pub bitrate: Option<f64>,
+    pub payload_size: Option<u16>,
+    pub burst: Option<u32>,
+    pub stream_id: Option<u16>,
+}
+
+impl Default for TargetSpec {
+    fn default() -> Self {
+        Self {
+            group: "[ff14::1a]:30000".parse().unwrap(),
+            rate: None,
+            bitrate: None,
+            payload_size: None,
+            burst: None,
+            stream_id: None,
+        }
+    }
+}
+
+impl FromStr for TargetSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut parts = spec.split(',');
+        let group = parts.next().unwrap_or_default();
+        let mut target = TargetSpec {
+            group: group
+                .parse()
+                .with_context(|| format!("invalid target group {group:?}"))?,
+            ..Default::default()
+        };
+        multicast_scope(&target.group.ip())?;
+
+        for option in parts {
+            let (key, value) = option
+                .split_once('=')
+                .with_context(|| format!("expected key=value but got {option:?}"))?;
+            match key {
+                "rate" => target.rate = Some(parse_rate(value)?),
+                "bitrate" => target.bitrate = Some(parse_bitrate(value)?),
+                "size" => target.payload_size = Some(value.parse()?),
+                "burst" => target.burst = Some(value.parse()?),
+                "stream" => target.stream_id = Some(value.parse()?),
+                _ => bail!("unknown target option {key:?}"),
+            }
+        }
+        if target.rate.is_some() && target.bitrate.is_some() {
+            bail!("rate and bitrate are mutually exclusive");
+        }
+
+        Ok(target)
+    }
+}
+
+impl std::fmt::Display for TargetSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.group)?;
+        if let Some(rate) = self.rate {
+            write!(f, ",rate={rate}")?;
+        }
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ",bitrate={bitrate}")?;
+        }
+        if let Some(payload_size) = self.payload_size {
+            write!(f, ",size={payload_size}")?;
+        }
+        if let Some(burst) = self.burst {
+            write!(f, ",burst={burst}")?;
+        }
+        if let Some(stream_id) = self.stream_id {
+            write!(f, ",stream={stream_id}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List multicast capable interfaces with their index, MTU, flags and addresses.
+    ListInterfaces,
+}
+
+/// Parse a send period, which has to be positive to give a packet rate.
+fn parse_period(value: &str) -> Result<Duration> {
+    let period = parse_duration(value)?;
+    if period.is_zero() {
+        bail!("send period must be positive");
+    }
+    Ok(period)
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
-    let period = args.period.unwrap_or(Duration::from_secs(1));
-
-    let socket = socket(
-        nix::sys::socket::AddressFamily::Inet6,
-        SockType::Datagram,
-        SockFlag::SOCK_NONBLOCK,
-        None,
-    )
-    .context("failed to open socket")?;
-
-    let bind_addr: SockaddrIn6 =
-        SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, args.src_port, 0, 0).into();
-    bind(socket, &bind_addr).context("failed to bind socket")?;
-
-    let net_if = get_interface_by_name(&args.interface_name).context("interface not found")?;
-    let src_addr = net_if
-        .ips
-        .iter()
-        .find(|ip| ip.is_ipv6())
-        .and_then(|ip| {
-            if let IpAddr::V6(ip) = ip.ip() {
-                Some(ip)
-            } else {
-                None
+
+    if let Some(Command::ListInterfaces) = args.command {
+        interface::print_interfaces();
+        return Ok(());
+    }
+
+    let net_if = select_interface(&args.interface)?;
+
+    let mut flags = Flags::empty();
+    flags.set(Flags::CRC, args.crc);
+    let key = args
+        .psk_file
+        .as_deref()
+        .map(PresharedKey::from_file)
+        .transpose()
+        .context("failed to read the pre-shared key")?;
+    flags.set(Flags::ENCRYPTED, key.is_some());
+
+    let mut shared_socket = None;
+    let mut streams = Vec::with_capacity(args.target_group.len());
+    for (idx, target) in args.target_group.iter().enumerate() {
+        let family = address_family(&target.group.ip());
+        let socket = match (args.separate_sockets, shared_socket) {
+            (false, Some((shared_family, socket))) => {
+                if shared_family != family {
+                    bail!("targets of different address families need --separate-sockets");
+                }
+                socket
             }
-        })
-        .context("no IPv6 address on interface")?;
-
-    // Set source IP address and interface.
-    let ipv6_info = in6_pktinfo {
-        ipi6_addr: in6_addr {
-            s6_addr: src_addr.octets(),
-        },
-        #[cfg(target_os = "android")]
-        ipi6_ifindex: net_if.index as i32,
-        #[cfg(not(target_os = "android"))]
-        ipi6_ifindex: net_if.index,
-    };
-
-    let cmsgs = &[ControlMessage::Ipv6PacketInfo(&ipv6_info)];
-    let dst_addr: SockaddrIn6 = args.target_group.parse::<SocketAddrV6>()?.into();
-
-    let mut counter = Wrapping(0_u32);
-    let mut payload = counter.0.to_be_bytes();
-
-    loop {
-        println!("Sending {}", counter.0);
-        sendmsg(
+            _ => {
+                let port = args.src_port.wrapping_add(idx as u16);
+                let socket = bind_socket(family, port).context("failed to open socket")?;
+                configure_socket(socket, family, &args)?;
+                shared_socket = Some((family, socket));
+                socket
+            }
+        };
+
+        let stream_id = target
+            .stream_id
+            .unwrap_or(args.stream_id.wrapping_add(idx as u16));
+        streams.push(Stream::new(
+            &args,
+            target,
+            socket,
+            &net_if,
+            flags,
+            stream_id,
+            key.as_ref(),
+        )?);
+    }
+
+    stop_on_sigint().context("failed to install SIGINT handler")?;
+
+    if let Some(path) = &args.replay {
+        let [stream] = &streams[..] else {
+            bail!("--replay sends to exactly one target group");
+        };
+        return replay(path, &stream.sender, args.duration);
+    }
+
+    let mut sockets: Vec<RawFd> = Vec::new();
+    for stream in &streams {
+        if !sockets.contains(&stream.socket) {
+            sockets.push(stream.socket);
+        }
+    }
+
+    let mut stats_writer = StatsWriter::stdout(args.output);
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut done_since = None;
+
+    while running() {
+        let timed_out = args
+            .duration
+            .is_some_and(|duration| started.elapsed() >= duration);
+        if streams.iter().all(|stream| stream.is_done(args.count)) {
+            let done_since = *done_since.get_or_insert_with(Instant::now);
+            if args.nack_buffer.is_none() || done_since.elapsed() >= NACK_LINGER {
+                break;
+            }
+        }
+        if timed_out {
+            break;
+        }
+
+        if let Some(writer) = &mut stats_writer {
+            if last_report.elapsed() >= args.stats_interval {
+                let interval = last_report.elapsed();
+                for stream in streams.iter_mut() {
+                    let record = stream.record(started.elapsed(), Some(interval));
+                    writer
+                        .write(&record)
+                        .context("failed to write statistics")?;
+                }
+                last_report = Instant::now();
+            }
+        }
+
+        if args.nack_buffer.is_some() {
+            for &socket in &sockets {
+                serve_nacks(socket, &mut streams)?;
+            }
+        }
+
+        let mut sent_any = false;
+        for stream in streams.iter_mut() {
+            sent_any |= stream.poll(args.count)?;
+        }
+
+        if !sent_any {
+            let mut wait = streams
+                .iter()
+                .filter(|stream| !stream.is_done(args.count))
+                .map(Stream::time_to_next)
+                .min()
+                .unwrap_or(NACK_LINGER);
+            if args.nack_buffer.is_some() {
+                wait = wait.min(NACK_POLL_INTERVAL);
+            }
+            std::thread::sleep(wait);
+        }
+    }
+
+    // Serving NACKs after the last packet does not count as sending time.
+    let elapsed = done_since.unwrap_or_else(Instant::now) - started;
+    for stream in &streams {
+        stream.report.print(&stream.name, elapsed);
+    }
+    if let Some(writer) = &mut stats_writer {
+        for stream in streams.iter_mut() {
+            let record = stream.record(elapsed, None);
+            writer
+                .write(&record)
+                .context("failed to write statistics")?;
+        }
+    }
+    if streams.len() > 1 {
+        let total: u64 = streams.iter().map(|stream| stream.report.packets).sum();
+        status!(
+            "Sent {total} packets in total over {} targets",
+            streams.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Queue the retransmissions requested by all pending NACKs on `socket`.
+fn serve_nacks(socket: RawFd, streams: &mut [Stream]) -> Result<()> {
+    while let Some((nack, source)) = recv_nack(socket).context("failed to receive NACK")? {
+        let nack = match nack {
+            Ok(nack) => nack,
+            Err(e) => {
+                status!("Ignoring datagram from {source}: {e}");
+                continue;
+            }
+        };
+        let stream = streams
+            .iter_mut()
+            .find(|stream| stream.socket == socket && stream.header.stream_id == nack.stream_id);
+        match stream {
+            Some(stream) => stream.retransmit(&nack),
+            None => status!(
+                "Ignoring NACK from {source} for unknown stream {}",
+                nack.stream_id
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Send the UDP payloads of a capture with their original spacing.
+fn replay(path: &Path, sender: &BatchSender, duration: Option<Duration>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut reader = PcapReader::new(BufReader::new(file)).context("failed to read capture")?;
+
+    let started = Instant::now();
+    let mut first_timestamp_ns = None;
+    let (mut packets, mut bytes, mut skipped, mut retries) = (0u64, 0u64, 0u64, 0u64);
+
+    while running() {
+        let Some(record) = reader.next_record().context("failed to read capture")? else {
+            break;
+        };
+        let Some(datagram) = reader.parse_udp(&record) else {
+            skipped += 1;
+            continue;
+        };
+
+        let first_timestamp_ns = *first_timestamp_ns.get_or_insert(record.timestamp_ns);
+        let offset = Duration::from_nanos(record.timestamp_ns.saturating_sub(first_timestamp_ns));
+        if duration.is_some_and(|duration| offset > duration) {
+            break;
+        }
+        // Packets which are already late are sent right away.
+        if let Some(wait) = offset.checked_sub(started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+
+        loop {
+            match sender.send(&[datagram.payload]) {
+                Ok(_) => break,
+                Err(Errno::EAGAIN | Errno::ENOBUFS) => {
+                    retries += 1;
+                    std::thread::sleep(Duration::from_micros(100));
+                }
+                Err(e) => return Err(e).context("failed to send"),
+            }
+        }
+        packets += 1;
+        bytes += datagram.payload.len() as u64;
+    }
+
+    status!(
+        "Replayed {packets} packets ({bytes} bytes) in {:.3?}, {retries} send buffer retries, \
+         skipped {skipped} non-UDP records",
+        started.elapsed()
+    );
+    Ok(())
+}
+
+/// Apply the TTL, loopback and traffic class options to a sender socket.
+fn configure_socket(socket: RawFd, family: AddressFamily, args: &Cli) -> Result<()> {
+    if let Some(hops) = args.hops {
+        send::set_multicast_hops(socket, family, hops).context("failed to set hop limit")?;
+    }
+    if args.no_loop {
+        send::set_multicast_loop(socket, family, false).context("failed to disable loopback")?;
+    }
+    let class = args.tclass.or(args.dscp.map(send::dscp_to_traffic_class));
+    if let Some(class) = class {
+        send::set_traffic_class(socket, family, class).context("failed to set traffic class")?;
+    }
+    Ok(())
+}
+
+/// Encode a packet, sealing the payload if it is encrypted.
+fn encode(sealer: &mut Option<Sealer>, header: &Header, payload: &[u8], out: &mut Vec<u8>) {
+    match sealer {
+        Some(sealer) => sealer.seal(header, payload, out),
+        None => packet::encode(header, payload, out),
+    }
+}
+
+/// Packets sent to one target group with one stream id.
+struct Stream {
+    name: String,
+    group: SocketAddr,
+    socket: RawFd,
+    sender: BatchSender,
+    bucket: TokenBucket,
+    burst: u32,
+    /// Print every packet like when sending periodically.
+    verbose: bool,
+    header: Header,
+    /// Payload of every packet, or message with a fragmenter.
+    payload: Vec<u8>,
+    fragmenter: Option<Fragmenter>,
+    counter: Wrapping<u32>,
+    /// Number of data packets or messages encoded so far.
+    generated: u64,
+    fec: Option<FecEncoder>,
+    retransmit: Option<RetransmitBuffer>,
+    sealer: Option<Sealer>,
+    /// Encoded packets not yet accepted by the socket.
+    batch: Vec<Vec<u8>>,
+    free: Vec<Vec<u8>>,
+    report: Report,
+}
+
+impl Stream {
+    fn new(
+        args: &Cli,
+        target: &TargetSpec,
+        socket: RawFd,
+        net_if: &NetworkInterface,
+        flags: Flags,
+        stream_id: u16,
+        key: Option<&PresharedKey>,
+    ) -> Result<Self> {
+        let group = scoped_group(target.group, net_if.index)?;
+        let src_addr = net_if
+            .ips
+            .iter()
+            .map(|ip| ip.ip())
+            .find(|ip| ip.is_ipv4() == group.is_ipv4())
+            .with_context(|| format!("no address of the family of {group} on interface"))?;
+
+        // Set source IP address and interface.
+        let sender = BatchSender::new(socket, group, PacketInfo::new(src_addr, net_if.index));
+
+        let mut header = Header {
+            flags,
+            stream_id,
+            ..Default::default()
+        };
+        let (payload, fragmenter, datagram_len) = match args.message_size {
+            Some(message_size) => {
+                header.flags.set(Flags::FRAGMENT, true);
+                let mtu = args.mtu.or_else(|| interface::mtu(net_if)).unwrap_or(1500);
+                let fragmenter = Fragmenter::new(max_chunk_len(mtu, src_addr.is_ipv4(), &header));
+                let fragments = fragmenter.fragment_count(message_size as usize);
+                if fragments > usize::from(u16::MAX) {
+                    bail!("{message_size} byte messages need too many fragments for MTU {mtu}");
+                }
+                let message: Vec<u8> = (0..message_size).map(|i| i as u8).collect();
+                let datagram_len =
+                    header.encoded_len(FRAGMENT_HEADER_LEN) * fragments + message.len();
+                (message, Some(fragmenter), datagram_len)
+            }
+            None => {
+                let payload =
+                    vec![0u8; usize::from(target.payload_size.unwrap_or(args.payload_size))];
+                let datagram_len = header.encoded_len(payload.len());
+                (payload, None, datagram_len)
+            }
+        };
+
+        let rate = target.rate.or(args.rate);
+        let bitrate = target.bitrate.or(args.bitrate);
+        let packet_rate = match (rate, bitrate) {
+            (Some(rate), _) => rate,
+            (_, Some(bitrate)) => bitrate / (datagram_len * 8) as f64,
+            _ => 1.0 / args.period.unwrap_or(Duration::from_secs(1)).as_secs_f64(),
+        };
+        let burst = target.burst.unwrap_or(args.burst).max(1);
+        let sealer = key
+            .map(|key| Sealer::new(key.clone()))
+            .transpose()
+            .context("failed to start an encryption session")?;
+
+        Ok(Self {
+            name: format!("{group} #{stream_id}"),
+            group,
             socket,
-            &[IoSlice::new(&payload)],
-            cmsgs,
-            MsgFlags::empty(),
-            Some(&dst_addr),
-        )?;
+            sender,
+            bucket: TokenBucket::new(packet_rate, burst),
+            burst,
+            verbose: rate.is_none() && bitrate.is_none(),
+            header,
+            payload,
+            fragmenter,
+            counter: Wrapping(0),
+            generated: 0,
+            fec: args.fec.map(FecEncoder::new),
+            retransmit: args.nack_buffer.map(RetransmitBuffer::new),
+            sealer,
+            batch: Vec::with_capacity(burst as usize),
+            free: Vec::new(),
+            report: Report::new(packet_rate, datagram_len),
+        })
+    }
+
+    fn is_done(&self, count: Option<u64>) -> bool {
+        count.is_some_and(|count| self.generated >= count) && self.batch.is_empty()
+    }
+
+    fn time_to_next(&self) -> Duration {
+        self.bucket.time_to_next()
+    }
+
+    /// Encode the next packet, followed by parity if it completes a span.
+    fn push_packet(&mut self, payload: &[u8]) {
+        self.header.sequence = self.counter.0;
+        self.header.timestamp_ns = now_nanos();
+        let mut datagram = self.free.pop().unwrap_or_default();
+        if let Some(retransmit) = &mut self.retransmit {
+            // Buffered unsealed, retransmissions are sealed again with their
+            // own nonce and the RETRANSMIT flag authenticated.
+            let mut header = self.header;
+            header.flags.set(Flags::ENCRYPTED, false);
+            packet::encode(&header, payload, &mut datagram);
+            retransmit.insert(self.header.sequence, &datagram);
+        }
+        encode(&mut self.sealer, &self.header, payload, &mut datagram);
+        self.batch.push(datagram);
+
+        if let Some(fec) = &mut self.fec {
+            fec.push(&self.header, payload, |header, payload| {
+                let mut datagram = self.free.pop().unwrap_or_default();
+                encode(&mut self.sealer, header, payload, &mut datagram);
+                self.batch.push(datagram);
+                self.report.parity += 1;
+            });
+        }
+
+        if self.verbose {
+            status!("Sending {} to {}", self.counter.0, self.name);
+        }
+        self.counter += 1;
+    }
+
+    /// Statistics record with the rates over the `interval` since the
+    /// previous record, or over `elapsed` for the summary without one.
+    fn record(&mut self, elapsed: Duration, interval: Option<Duration>) -> Record {
+        let report = &mut self.report;
+        let (packets, bytes) = match interval {
+            Some(_) => (
+                report.packets - report.reported.0,
+                report.bytes - report.reported.1,
+            ),
+            None => (report.packets, report.bytes),
+        };
+        report.reported = (report.packets, report.bytes);
+        let seconds = interval.unwrap_or(elapsed).as_secs_f64();
+
+        Record::new()
+            .field(
+                "record",
+                match interval {
+                    Some(_) => "interval",
+                    None => "summary",
+                },
+            )
+            .field("timestamp_ns", now_nanos())
+            .field("elapsed_s", elapsed.as_secs_f64())
+            .field("group", self.group.to_string())
+            .field("stream_id", self.header.stream_id)
+            .field("packets", report.packets)
+            .field("bytes", report.bytes)
+            .field("packet_rate", packets as f64 / seconds)
+            .field("bitrate", (bytes * 8) as f64 / seconds)
+            .field("requested_rate", report.requested_rate)
+            .field("messages", report.messages)
+            .field("parity", report.parity)
+            .field("nacks", report.nacks)
+            .field("retransmitted", report.retransmitted)
+            .field("retries", report.retries)
+    }
+
+    /// Queue the packets requested by `nack` which are still buffered.
+    fn retransmit(&mut self, nack: &Nack) {
+        let Some(retransmit) = &mut self.retransmit else {
+            return;
+        };
+        self.report.nacks += 1;
+        self.report.unavailable += retransmit.request(nack);
+    }
+
+    /// Send the packets the token bucket allows. Returns whether anything
+    /// was sent or is still pending.
+    fn poll(&mut self, count: Option<u64>) -> Result<bool> {
+        if self.batch.is_empty() {
+            let remaining = count.map_or(u64::MAX, |count| count.saturating_sub(self.generated));
+            let remaining: u32 = remaining.try_into().unwrap_or(u32::MAX);
+            // Retransmissions take tokens like new packets, but at most half
+            // of a burst while new packets are due, so NACKs slow the stream
+            // down without stalling it.
+            let share = match remaining {
+                0 => self.burst,
+                _ => self.burst.div_ceil(2),
+            };
+            let queued = self.retransmit.as_ref().map_or(0, RetransmitBuffer::queued);
+            let repairs = self
+                .bucket
+                .take(share.min(queued.try_into().unwrap_or(u32::MAX)));
+            let taken = self.bucket.take((self.burst - repairs).min(remaining));
+            if taken == 0 && repairs == 0 {
+                return Ok(false);
+            }
+
+            if let Some(retransmit) = &mut self.retransmit {
+                let retransmitted = retransmit.retransmit_queued(repairs, |packet| {
+                    let mut header = packet.header;
+                    header.flags.set(Flags::ENCRYPTED, self.sealer.is_some());
+                    let mut datagram = self.free.pop().unwrap_or_default();
+                    encode(&mut self.sealer, &header, packet.payload, &mut datagram);
+                    self.batch.push(datagram);
+                });
+                self.report.retransmitted += u64::from(retransmitted);
+            }
+
+            let payload = std::mem::take(&mut self.payload);
+            for _ in 0..taken {
+                match self.fragmenter.take() {
+                    Some(mut fragmenter) => {
+                        // The message size was checked to fit.
+                        fragmenter
+                            .split(&payload, |fragment| self.push_packet(fragment))
+                            .expect("message too large");
+                        self.fragmenter = Some(fragmenter);
+                        self.report.messages += 1;
+                    }
+                    None => self.push_packet(&payload),
+                }
+                self.generated += 1;
+            }
+            self.payload = payload;
+        }
+
+        match self.sender.send(&self.batch) {
+            Ok(sent) => {
+                self.report.packets += sent as u64;
+                let sent_bytes: usize = self.batch[..sent].iter().map(Vec::len).sum();
+                self.report.bytes += sent_bytes as u64;
+                self.free.extend(self.batch.drain(..sent));
+            }
+            // The socket send buffer is full, retry the remaining packets.
+            Err(Errno::EAGAIN | Errno::ENOBUFS) => {
+                self.report.retries += 1;
+                std::thread::sleep(Duration::from_micros(100));
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to send to {}", self.name)),
+        }
+
+        Ok(true)
+    }
+}
+
+/// Achieved versus requested send rate.
+struct Report {
+    requested_rate: f64,
+    /// Encoded bytes of a packet or of all fragments of a message.
+    datagram_len: usize,
+    /// Sent datagrams, including parity and retransmissions.
+    packets: u64,
+    bytes: u64,
+    /// Generated parity packets.
+    parity: u64,
+    /// Generated messages with `--message-size`.
+    messages: u64,
+    /// Received NACKs.
+    nacks: u64,
+    /// Packets sent again on NACKs.
+    retransmitted: u64,
+    /// Requested packets which were not buffered anymore.
+    unavailable: u64,
+    /// Number of times the socket send buffer was full.
+    retries: u64,
+    /// `packets` and `bytes` at the previous interval record.
+    reported: (u64, u64),
+}
+
+impl Report {
+    fn new(requested_rate: f64, datagram_len: usize) -> Self {
+        Self {
+            requested_rate,
+            datagram_len,
+            packets: 0,
+            bytes: 0,
+            parity: 0,
+            messages: 0,
+            nacks: 0,
+            retransmitted: 0,
+            unavailable: 0,
+            retries: 0,
+            reported: (0, 0),
+        }
+    }
 
-        std::thread::sleep(period);
+    fn print(&self, name: &str, elapsed: Duration) {
+        let rate = self.packets as f64 / elapsed.as_secs_f64();
+        let bit_rate = (self.bytes * 8) as f64 / elapsed.as_secs_f64();
+        let bits = self.datagram_len as f64 * 8.0;
 
-        counter += 1;
-        payload = counter.0.to_be_bytes();
+        status!(
+            "[{name}] Sent {} packets ({} bytes) in {elapsed:.3?}, {} send buffer retries",
+            self.packets,
+            self.bytes,
+            self.retries
+        );
+        if self.parity > 0 {
+            status!("[{name}] {} of the packets were FEC parity", self.parity);
+        }
+        if self.nacks > 0 {
+            status!(
+                "[{name}] {} NACKs, {} packets retransmitted, {} no longer buffered",
+                self.nacks,
+                self.retransmitted,
+                self.unavailable
+            );
+        }
+        if self.messages > 0 {
+            let message_rate = self.messages as f64 / elapsed.as_secs_f64();
+            status!(
+                "[{name}] Sent {} messages, achieved {message_rate:.1} msg/s, {bit_rate:.0} bit/s \
+                 (requested {:.1} msg/s, {:.0} bit/s)",
+                self.messages,
+                self.requested_rate,
+                self.requested_rate * bits,
+            );
+            return;
+        }
+        status!(
+            "[{name}] Achieved {rate:.1} pkt/s, {bit_rate:.0} bit/s (requested {:.1} pkt/s, {:.0} bit/s)",
+            self.requested_rate,
+            self.requested_rate * bits,
+        );
     }
 }
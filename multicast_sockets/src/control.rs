@@ -0,0 +1,329 @@
+//! Line based control channel to change a running receiver.
+//!
+//! Commands are read from stdin or from connections to a Unix domain socket
+//! on background threads and handed to the receive loop, which polls them
+//! with [`ControlChannel::try_recv`] between batches. Every command is
+//! answered with one line, which is written back to the connection for the
+//! Unix socket. For stdin, the replies are only logged as status lines.
+//!
+//! ```text
+//! join ff14::1b
+//! join 232.1.1.1 192.0.2.1
+//! leave ff14::1b
+//! list
+//! stats
+//! ```
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+/// Where control commands are read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlSource {
+    Stdin,
+    UnixSocket(PathBuf),
+}
+
+impl FromStr for ControlSource {
+    type Err = std::convert::Infallible;
+
+    /// Parse `-` or `stdin`, or else a Unix socket path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "-" | "stdin" => Ok(Self::Stdin),
+            path => Ok(Self::UnixSocket(PathBuf::from(path))),
+        }
+    }
+}
+
+/// A command to change the memberships or query the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Join `group`, source-specific if a source is given.
+    Join {
+        group: IpAddr,
+        source: Option<IpAddr>,
+    },
+    /// Leave a membership added before.
+    Leave {
+        group: IpAddr,
+        source: Option<IpAddr>,
+    },
+    /// List the current memberships.
+    List,
+    /// Print the statistics now.
+    Stats,
+}
+
+/// A control command could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCommandError(String);
+
+impl fmt::Display for ParseCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseCommandError {}
+
+impl FromStr for ControlCommand {
+    type Err = ParseCommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+
+        let parse_addr = |word: Option<&str>| {
+            word.map(|word| {
+                word.parse::<IpAddr>()
+                    .map_err(|_| ParseCommandError(format!("invalid address {word:?}")))
+            })
+            .transpose()
+        };
+        let mut membership = || {
+            let group = parse_addr(words.next())?
+                .ok_or_else(|| ParseCommandError(format!("{command} needs a group address")))?;
+            let source = parse_addr(words.next())?;
+            if let Some(extra) = words.next() {
+                return Err(ParseCommandError(format!("unexpected {extra:?}")));
+            }
+            Ok((group, source))
+        };
+
+        match command {
+            "join" => membership().map(|(group, source)| Self::Join { group, source }),
+            "leave" => membership().map(|(group, source)| Self::Leave { group, source }),
+            "list" => Ok(Self::List),
+            "stats" => Ok(Self::Stats),
+            "" => Err(ParseCommandError("empty command".to_owned())),
+            _ => Err(ParseCommandError(format!(
+                "unknown command {command:?}, expected join, leave, list or stats"
+            ))),
+        }
+    }
+}
+
+/// A received command with the channel to send the one line reply to.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: Sender<String>,
+}
+
+impl ControlRequest {
+    /// Answer the request. Replies to closed connections are dropped.
+    pub fn reply(self, reply: impl Into<String>) {
+        let _ = self.reply.send(reply.into());
+    }
+}
+
+/// Commands received on background threads.
+pub struct ControlChannel {
+    requests: Receiver<ControlRequest>,
+    /// Unix socket path which is removed on drop.
+    socket_path: Option<PathBuf>,
+}
+
+impl ControlChannel {
+    /// Start reading commands from `source`.
+    pub fn open(source: &ControlSource) -> io::Result<Self> {
+        let (requests, receiver) = mpsc::channel();
+        let socket_path = match source {
+            ControlSource::Stdin => {
+                thread::spawn(move || serve_stdin(requests));
+                None
+            }
+            ControlSource::UnixSocket(path) => {
+                let listener = bind_unix_socket(path)?;
+                thread::spawn(move || serve_unix_socket(listener, requests));
+                Some(path.clone())
+            }
+        };
+        Ok(Self {
+            requests: receiver,
+            socket_path,
+        })
+    }
+
+    /// Take the next pending request without blocking.
+    pub fn try_recv(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ControlChannel {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Bind a listener at `path`, replacing a stale socket of an earlier run.
+/// Any other file at `path` is kept.
+fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale_socket(path) => {
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Whether `path` is a Unix socket which nothing listens on.
+fn is_stale_socket(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err()
+}
+
+fn serve_stdin(requests: Sender<ControlRequest>) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        // The receive loop logs the replies to valid commands.
+        match line.parse() {
+            Ok(command) => {
+                if request(command, &requests).is_none() {
+                    break;
+                }
+            }
+            Err(e) => crate::status!("Control: error: {e}"),
+        }
+    }
+}
+
+fn serve_unix_socket(listener: UnixListener, requests: Sender<ControlRequest>) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let requests = requests.clone();
+        thread::spawn(move || serve_connection(stream, requests));
+    }
+}
+
+fn serve_connection(stream: UnixStream, requests: Sender<ControlRequest>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match handle_line(&line, &requests) {
+            Some(reply) => writeln!(writer, "{reply}")?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Hand one command line to the receive loop and wait for the reply. Returns
+/// `None` once the receive loop is gone.
+fn handle_line(line: &str, requests: &Sender<ControlRequest>) -> Option<String> {
+    match line.parse() {
+        Ok(command) => request(command, requests),
+        Err(e) => Some(format!("error: {e}")),
+    }
+}
+
+/// Hand a command to the receive loop and wait for the reply.
+fn request(command: ControlCommand, requests: &Sender<ControlRequest>) -> Option<String> {
+    let (reply, replies) = mpsc::channel();
+    requests.send(ControlRequest { command, reply }).ok()?;
+    replies.recv().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let group: IpAddr = "ff14::1b".parse().unwrap();
+        let source: IpAddr = "fd00::1".parse().unwrap();
+
+        assert_eq!(
+            "join ff14::1b".parse(),
+            Ok(ControlCommand::Join {
+                group,
+                source: None
+            })
+        );
+        assert_eq!(
+            "  leave ff14::1b fd00::1 ".parse(),
+            Ok(ControlCommand::Leave {
+                group,
+                source: Some(source)
+            })
+        );
+        assert_eq!("list".parse(), Ok(ControlCommand::List));
+        assert_eq!("stats".parse(), Ok(ControlCommand::Stats));
+
+        assert!("join".parse::<ControlCommand>().is_err());
+        assert!("join ff14::1b fd00::1 fd00::2"
+            .parse::<ControlCommand>()
+            .is_err());
+        assert!("leave group".parse::<ControlCommand>().is_err());
+        assert!("restart".parse::<ControlCommand>().is_err());
+        assert!("".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn unix_socket_round_trip() {
+        let path = std::env::temp_dir().join(format!("mcsk-control-{}.sock", std::process::id()));
+        let channel = ControlChannel::open(&ControlSource::UnixSocket(path.clone())).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        writeln!(&stream, "bogus").unwrap();
+        assert!(lines.next().unwrap().unwrap().starts_with("error: "));
+
+        writeln!(&stream, "join 239.1.2.3").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let request = loop {
+            if let Some(request) = channel.try_recv() {
+                break request;
+            }
+            assert!(Instant::now() < deadline, "no request received");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(
+            request.command,
+            ControlCommand::Join {
+                group: "239.1.2.3".parse().unwrap(),
+                source: None
+            }
+        );
+        request.reply("ok");
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+
+        drop(channel);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replaces_only_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("mcsk-control-{}.file", std::process::id()));
+        fs::write(&path, "data").unwrap();
+        let e = bind_unix_socket(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+
+        // Dropping the listener leaves the socket file behind.
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(bind_unix_socket(&path).is_err());
+        drop(listener);
+        bind_unix_socket(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
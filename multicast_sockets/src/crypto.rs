@@ -0,0 +1,520 @@
+//! Payload encryption and authentication with ChaCha20-Poly1305 under a
+//! pre-shared key.
+//!
+//! The payload of a packet with [`Flags::ENCRYPTED`] is sealed as
+//!
+//! ```text
+//! +-------------------------------+---------------+-------------------+
+//! |  nonce (session id, counter)  |  ciphertext   |  Poly1305 tag     |
+//! +-------------------------------+---------------+-------------------+
+//!              12 bytes                                 16 bytes
+//! ```
+//!
+//! with the packet header as associated data, so the header stays readable
+//! (e.g. for the steering of [`crate::fanout`]) but can not be changed.
+//! Retransmissions differ in [`Flags::RETRANSMIT`] and are sealed again.
+//!
+//! Every sender stream draws a random 8 byte session id and counts its
+//! packets in the remaining 4 bytes of the nonce, so nonces are not reused
+//! across streams and restarts. The receiver rejects replayed packets with a
+//! [`ReplayGuard`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Nonce, Tag,
+};
+
+use crate::packet::{self, Flags, Header, Packet, CRC_LEN, HEADER_LEN};
+
+/// Length of the pre-shared key in bytes.
+pub const KEY_LEN: usize = 32;
+/// Length of the nonce in front of the ciphertext.
+pub const NONCE_LEN: usize = 12;
+/// Length of the authentication tag after the ciphertext.
+pub const TAG_LEN: usize = 16;
+/// Length of the session id at the start of the nonce.
+const SESSION_LEN: usize = 8;
+/// Number of sequence numbers below the highest one which are tracked for
+/// replays. Older packets are rejected, so this also bounds how late
+/// retransmissions may arrive.
+const REPLAY_WINDOW: u32 = 4096;
+/// Number of sessions per stream id tracked for replays, which covers
+/// several restarts of a sender within the lifetime of the receiver.
+const REPLAY_SESSIONS: usize = 4;
+
+/// A sealed packet was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The packet is not encrypted although a key is configured.
+    NotEncrypted,
+    /// The sealed payload is shorter than nonce and tag.
+    TooShort(usize),
+    /// The tag does not match, the packet was changed or sealed with another
+    /// key.
+    TagMismatch,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::NotEncrypted => write!(f, "packet is not encrypted"),
+            AuthError::TooShort(len) => write!(f, "sealed payload of {len} bytes too short"),
+            AuthError::TagMismatch => write!(f, "authentication failed"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Pre-shared key for sealing and opening packets.
+#[derive(Clone)]
+pub struct PresharedKey(ChaCha20Poly1305);
+
+impl PresharedKey {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self(ChaCha20Poly1305::new(key.into()))
+    }
+
+    /// Read a key file of 32 raw bytes or of 64 hexadecimal digits, e.g.
+    /// created with `head -c 32 /dev/urandom > psk`.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read(path)?;
+        if let Ok(key) = <[u8; KEY_LEN]>::try_from(&content[..]) {
+            return Ok(Self::new(&key));
+        }
+        parse_hex(content.trim_ascii())
+            .map(|key| Self::new(&key))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected 32 bytes or 64 hexadecimal digits",
+                )
+            })
+    }
+
+    /// Decrypt the payload of `packet`, decoded from `datagram`, into
+    /// `plaintext` and return the packet with the plaintext as payload.
+    pub fn open<'p>(
+        &self,
+        datagram: &[u8],
+        packet: &Packet,
+        plaintext: &'p mut Vec<u8>,
+    ) -> Result<Packet<'p>, AuthError> {
+        if !packet.header.flags.contains(Flags::ENCRYPTED) {
+            return Err(AuthError::NotEncrypted);
+        }
+        let sealed = packet.payload;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(AuthError::TooShort(sealed.len()));
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        plaintext.clear();
+        plaintext.extend_from_slice(ciphertext);
+        self.0
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &associated_data(datagram),
+                plaintext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| AuthError::TagMismatch)?;
+
+        Ok(Packet {
+            header: packet.header,
+            payload: plaintext,
+        })
+    }
+}
+
+/// Seals the packets of one stream with fresh nonces.
+pub struct Sealer {
+    key: PresharedKey,
+    session: u64,
+    counter: u32,
+    /// Sealed payload before encryption.
+    buffer: Vec<u8>,
+}
+
+impl Sealer {
+    /// Start a session with a random id.
+    pub fn new(key: PresharedKey) -> io::Result<Self> {
+        let mut session = [0; SESSION_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut session)?;
+        Ok(Self {
+            key,
+            session: u64::from_be_bytes(session),
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Encode a packet like [`packet::encode`] with the payload sealed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the header lacks [`Flags::ENCRYPTED`], or if the sealed
+    /// payload is longer than `u16::MAX` bytes.
+    pub fn seal(&mut self, header: &Header, payload: &[u8], out: &mut Vec<u8>) {
+        assert!(
+            header.flags.contains(Flags::ENCRYPTED),
+            "header not flagged"
+        );
+
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..SESSION_LEN].copy_from_slice(&self.session.to_be_bytes());
+        nonce[SESSION_LEN..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == 0 {
+            // Continue in the next session instead of reusing nonces.
+            self.session = self.session.wrapping_add(1);
+        }
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&nonce);
+        self.buffer.extend_from_slice(payload);
+        self.buffer.extend_from_slice(&[0; TAG_LEN]);
+        packet::encode(header, &self.buffer, out);
+
+        let start = HEADER_LEN + NONCE_LEN;
+        let end = start + payload.len();
+        let associated_data = associated_data(out);
+        let tag = self
+            .key
+            .0
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &associated_data,
+                &mut out[start..end],
+            )
+            .expect("payload too long for ChaCha20-Poly1305");
+        out[end..end + TAG_LEN].copy_from_slice(&tag);
+
+        // The CRC was computed over the plaintext.
+        if header.flags.contains(Flags::CRC) {
+            let content_len = out.len() - CRC_LEN;
+            let crc = crc32fast::hash(&out[..content_len]);
+            out[content_len..].copy_from_slice(&crc.to_be_bytes());
+        }
+    }
+}
+
+/// The session id of the sender of a sealed payload.
+pub fn session_id(sealed: &[u8]) -> Option<u64> {
+    let session = sealed.get(..SESSION_LEN)?;
+    Some(u64::from_be_bytes(session.try_into().ok()?))
+}
+
+/// Rejects replayed packets.
+///
+/// The packets are tracked per session id and stream id, which are both
+/// authenticated unlike the address of the sender, so packets replayed from
+/// another address are rejected as well. Data and parity packets are tracked
+/// in separate windows, as parity packets carry the sequence number of the
+/// first packet they cover. Packets of a session which was never received
+/// before, e.g. recorded before the receiver started, are accepted once.
+/// Only the [`REPLAY_SESSIONS`] most recently used sessions of a stream id
+/// are tracked, so packets of older sessions count as never received.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayGuard {
+    /// Sessions by stream id, the most recently used last.
+    streams: HashMap<u16, VecDeque<SessionWindows>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct SessionWindows {
+    session: u64,
+    data: ReplayWindow,
+    parity: ReplayWindow,
+}
+
+impl ReplayGuard {
+    /// Whether the authenticated packet with `header` of `session` was not
+    /// received before. Accepted packets are recorded.
+    pub fn accept(&mut self, header: &Header, session: u64) -> bool {
+        let sessions = self.streams.entry(header.stream_id).or_default();
+        let windows = match sessions
+            .iter()
+            .position(|windows| windows.session == session)
+        {
+            Some(idx) => sessions.remove(idx).expect("position in range"),
+            None => SessionWindows {
+                session,
+                ..Default::default()
+            },
+        };
+        if sessions.len() == REPLAY_SESSIONS {
+            sessions.pop_front();
+        }
+        sessions.push_back(windows);
+        let windows = sessions.back_mut().expect("just pushed");
+        match header.flags.contains(Flags::PARITY) {
+            true => windows.parity.accept(header.sequence),
+            false => windows.data.accept(header.sequence),
+        }
+    }
+}
+
+/// Sequence numbers seen within [`REPLAY_WINDOW`] below the highest one, as
+/// in IPsec (RFC 4303 section 3.4.3).
+#[derive(Debug, Clone)]
+struct ReplayWindow {
+    highest: Option<u32>,
+    /// Bit `sequence % REPLAY_WINDOW` is set once the sequence was seen.
+    seen: Box<[u64]>,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self {
+            highest: None,
+            seen: vec![0; REPLAY_WINDOW as usize / 64].into_boxed_slice(),
+        }
+    }
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, sequence: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.mark(sequence);
+            return true;
+        };
+
+        let ahead = sequence.wrapping_sub(highest) as i32;
+        if ahead > 0 {
+            // Forget the sequence numbers which fall out of the window.
+            if ahead as u32 >= REPLAY_WINDOW {
+                self.seen.fill(0);
+            } else {
+                for skipped in 1..=ahead as u32 {
+                    self.clear(highest.wrapping_add(skipped));
+                }
+            }
+            self.highest = Some(sequence);
+        } else if ahead.unsigned_abs() >= REPLAY_WINDOW || self.is_seen(sequence) {
+            return false;
+        }
+        self.mark(sequence);
+        true
+    }
+
+    fn bit(sequence: u32) -> (usize, u64) {
+        let index = sequence % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_seen(&self, sequence: u32) -> bool {
+        let (word, mask) = Self::bit(sequence);
+        self.seen[word] & mask != 0
+    }
+
+    fn mark(&mut self, sequence: u32) {
+        let (word, mask) = Self::bit(sequence);
+        self.seen[word] |= mask;
+    }
+
+    fn clear(&mut self, sequence: u32) {
+        let (word, mask) = Self::bit(sequence);
+        self.seen[word] &= !mask;
+    }
+}
+
+/// A copy of the header of `datagram`, which is sealed in place.
+fn associated_data(datagram: &[u8]) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header.copy_from_slice(&datagram[..HEADER_LEN]);
+    header
+}
+
+fn parse_hex(digits: &[u8]) -> Option<[u8; KEY_LEN]> {
+    if digits.len() != 2 * KEY_LEN {
+        return None;
+    }
+    let mut key = [0; KEY_LEN];
+    for (byte, pair) in key.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    fn header(flags: Flags, sequence: u32) -> Header {
+        let mut header = Header {
+            flags,
+            stream_id: 3,
+            sequence,
+            timestamp_ns: 1_690_000_000_000_000_000 + u64::from(sequence),
+        };
+        header.flags.set(Flags::ENCRYPTED, true);
+        header
+    }
+
+    fn sealed(sealer: &mut Sealer, flags: Flags, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        sealer.seal(&header(flags, 1), payload, &mut datagram);
+        datagram
+    }
+
+    fn open(key: &PresharedKey, datagram: &[u8]) -> Result<Vec<u8>, AuthError> {
+        let packet = packet::decode(datagram).unwrap();
+        let mut plaintext = Vec::new();
+        key.open(datagram, &packet, &mut plaintext)
+            .map(|packet| packet.payload.to_vec())
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = PresharedKey::new(&KEY);
+        let mut sealer = Sealer::new(key.clone()).unwrap();
+        for flags in [Flags::empty(), Flags::CRC] {
+            let datagram = sealed(&mut sealer, flags, b"hello");
+            assert_eq!(datagram.len(), header(flags, 1).encoded_len(5));
+            assert!(!datagram.windows(5).any(|window| window == b"hello"));
+            assert_eq!(open(&key, &datagram).unwrap(), b"hello");
+        }
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let mut sealer = Sealer::new(PresharedKey::new(&KEY)).unwrap();
+        let first = sealed(&mut sealer, Flags::empty(), b"hello");
+        let second = sealed(&mut sealer, Flags::empty(), b"hello");
+        assert_ne!(first[HEADER_LEN..], second[HEADER_LEN..]);
+
+        let other = Sealer::new(PresharedKey::new(&KEY)).unwrap();
+        assert_ne!(other.session, sealer.session);
+    }
+
+    #[test]
+    fn rejects_tampered_packets() {
+        let key = PresharedKey::new(&KEY);
+        let mut sealer = Sealer::new(key.clone()).unwrap();
+        let datagram = sealed(&mut sealer, Flags::empty(), b"hello");
+
+        // Payload, tag and every header field except the magic and version
+        // which are checked on decoding.
+        for idx in 5..datagram.len() {
+            let mut tampered = datagram.clone();
+            tampered[idx] ^= 0x10;
+            if let Ok(packet) = packet::decode(&tampered) {
+                let mut plaintext = Vec::new();
+                assert!(key.open(&tampered, &packet, &mut plaintext).is_err());
+            }
+        }
+
+        let other = PresharedKey::new(&[8; KEY_LEN]);
+        assert_eq!(open(&other, &datagram), Err(AuthError::TagMismatch));
+    }
+
+    #[test]
+    fn retransmissions_are_sealed_again() {
+        let key = PresharedKey::new(&KEY);
+        let mut sealer = Sealer::new(key.clone()).unwrap();
+        let datagram = sealed(&mut sealer, Flags::CRC, b"hello");
+
+        // Marking the original as retransmission breaks the seal.
+        let packet = packet::decode(&datagram).unwrap();
+        let mut header = packet.header;
+        header.flags.set(Flags::RETRANSMIT, true);
+        let mut retransmission = Vec::new();
+        packet::encode(&header, packet.payload, &mut retransmission);
+        assert_eq!(open(&key, &retransmission), Err(AuthError::TagMismatch));
+
+        sealer.seal(&header, b"hello", &mut retransmission);
+        assert_eq!(open(&key, &retransmission).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn rejects_unencrypted_packets() {
+        let key = PresharedKey::new(&KEY);
+        let mut datagram = Vec::new();
+        packet::encode(&Header::default(), b"hello", &mut datagram);
+        assert_eq!(open(&key, &datagram), Err(AuthError::NotEncrypted));
+    }
+
+    #[test]
+    fn parses_hex_keys() {
+        let hex = "07".repeat(KEY_LEN);
+        assert_eq!(parse_hex(hex.as_bytes()), Some(KEY));
+        assert_eq!(parse_hex(&hex.as_bytes()[2..]), None);
+        assert_eq!(parse_hex("zz".repeat(KEY_LEN).as_bytes()), None);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        assert!(window.accept(12));
+        // Late but within the window.
+        assert!(window.accept(11));
+        assert!(!window.accept(11));
+        assert!(window.accept(10 + REPLAY_WINDOW));
+        // Too old, or seen before the window moved.
+        assert!(!window.accept(10));
+        assert!(!window.accept(12));
+        // Not seen but still within the window.
+        assert!(window.accept(13));
+
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(u32::MAX));
+        assert!(window.accept(0));
+        assert!(!window.accept(u32::MAX));
+    }
+
+    #[test]
+    fn replay_guard_sessions() {
+        let mut guard = ReplayGuard::default();
+        assert!(guard.accept(&header(Flags::empty(), 5), 1));
+        assert!(!guard.accept(&header(Flags::empty(), 5), 1));
+        // Parity of the same sequence number is tracked separately.
+        assert!(guard.accept(&header(Flags::PARITY, 5), 1));
+        // Retransmissions of lost packets only.
+        assert!(guard.accept(&header(Flags::RETRANSMIT, 4), 1));
+        assert!(!guard.accept(&header(Flags::RETRANSMIT, 4), 1));
+        assert!(!guard.accept(&header(Flags::RETRANSMIT, 5), 1));
+
+        // A restarted sender.
+        assert!(guard.accept(&header(Flags::empty(), 0), 2));
+        assert!(guard.accept(&header(Flags::empty(), 1), 2));
+        // The old session can not be replayed.
+        assert!(!guard.accept(&header(Flags::empty(), 5), 1));
+
+        // Other streams of a sender are tracked separately.
+        let other_stream = Header {
+            stream_id: 4,
+            ..header(Flags::empty(), 5)
+        };
+        assert!(guard.accept(&other_stream, 1));
+        assert!(!guard.accept(&other_stream, 1));
+    }
+
+    #[test]
+    fn replay_guard_forgets_least_recently_used_sessions() {
+        let mut guard = ReplayGuard::default();
+        for session in 0..REPLAY_SESSIONS as u64 {
+            assert!(guard.accept(&header(Flags::empty(), 0), session));
+        }
+        // Session 0 stays tracked while in use, session 1 is forgotten.
+        assert!(guard.accept(&header(Flags::empty(), 1), 0));
+        assert!(guard.accept(&header(Flags::empty(), 0), 10));
+        assert!(!guard.accept(&header(Flags::empty(), 0), 0));
+        assert!(guard.accept(&header(Flags::empty(), 0), 1));
+        assert_eq!(guard.streams[&3].len(), REPLAY_SESSIONS);
+    }
+}
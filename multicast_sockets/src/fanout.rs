@@ -0,0 +1,187 @@
+//! Reception with several sockets on one port, each served by its own
+//! worker thread.
+//!
+//! Every worker socket is bound to the group port with `SO_REUSEPORT`. Linux
+//! only balances unicast datagrams over the sockets of a reuseport group and
+//! delivers a copy of every multicast datagram to each of them, so a classic
+//! BPF socket filter on every socket keeps only the share of its worker. The
+//! share is selected by [`Steering`] such that all packets of a stream arrive
+//! at the same worker, which keeps the statistics of the workers disjoint.
+//!
+//! The kernel counts every copy rejected by a filter as a drop of its socket
+//! (`sk_drops`, reported with `SO_RXQ_OVFL`) and in the system-wide UDP
+//! `InErrors` counter (`/proc/net/snmp` and `snmp6`). With `N` workers,
+//! `(N-1)/N` of all received multicast datagrams show up there as receive
+//! errors, which [`buffer_drops`] takes out of the drops of the workers.
+
+use std::{fmt, os::fd::RawFd, str::FromStr};
+
+use nix::{
+    libc::{
+        self, sock_filter, sock_fprog, BPF_ABS, BPF_ADD, BPF_ALU, BPF_H, BPF_IMM, BPF_JA, BPF_JEQ,
+        BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_LEN, BPF_MISC, BPF_MOD, BPF_RET, BPF_TAX, BPF_W,
+        BPF_X,
+    },
+    sched::{sched_setaffinity, CpuSet},
+    sys::socket::{setsockopt, sockopt, AddressFamily},
+    unistd::Pid,
+};
+
+use crate::{packet, receive, set_option};
+
+/// Not exported by libc for all Linux targets.
+const SO_ATTACH_FILTER: libc::c_int = 26;
+/// Offset of the payload in the data seen by socket filters, which starts
+/// with the UDP header.
+const UDP_HEADER_LEN: u32 = 8;
+/// Offset of the stream id in the packet header.
+const STREAM_ID_OFFSET: u32 = 6;
+
+/// How datagrams are distributed over the workers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Steering {
+    /// By the UDP source port and the stream id of the packet header, which
+    /// also spreads several streams of one sender.
+    #[default]
+    Stream,
+    /// By the UDP source port only, for datagrams without packet header.
+    SourcePort,
+}
+
+impl FromStr for Steering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(Self::Stream),
+            "source-port" => Ok(Self::SourcePort),
+            _ => Err(format!(
+                "unknown steering {s:?}, expected stream or source-port"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Steering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stream => write!(f, "stream"),
+            Self::SourcePort => write!(f, "source-port"),
+        }
+    }
+}
+
+/// Open the socket of `worker` out of `workers` like
+/// [`receive::bind_socket`], but with `SO_REUSEPORT` and a filter which only
+/// accepts the share of the worker.
+pub fn bind_worker_socket(
+    family: AddressFamily,
+    port: u16,
+    steering: Steering,
+    workers: u32,
+    worker: u32,
+) -> nix::Result<RawFd> {
+    receive::bind_socket_with(family, port, |socket| {
+        setsockopt(socket, sockopt::ReusePort, &true)?;
+        if workers > 1 {
+            attach_filter(socket, &steering_program(steering, workers, worker))?;
+        }
+        Ok(())
+    })
+}
+
+/// Classic BPF program which accepts a datagram if the hash selected by
+/// `steering` modulo `workers` equals `worker`.
+pub fn steering_program(steering: Steering, workers: u32, worker: u32) -> Vec<sock_filter> {
+    let mut program = Vec::new();
+    if steering == Steering::Stream {
+        // X = stream id, or 0 for datagrams too short for a packet header.
+        program.extend([
+            stmt(BPF_LD | BPF_W | BPF_LEN, 0),
+            jump(
+                BPF_JMP | BPF_JGE | BPF_K,
+                UDP_HEADER_LEN + packet::HEADER_LEN as u32,
+                0,
+                2,
+            ),
+            stmt(BPF_LD | BPF_H | BPF_ABS, UDP_HEADER_LEN + STREAM_ID_OFFSET),
+            stmt(BPF_JMP | BPF_JA, 1),
+            stmt(BPF_LD | BPF_IMM, 0),
+            stmt(BPF_MISC | BPF_TAX, 0),
+        ]);
+    }
+    // A = UDP source port (+ X).
+    program.push(stmt(BPF_LD | BPF_H | BPF_ABS, 0));
+    if steering == Steering::Stream {
+        program.push(stmt(BPF_ALU | BPF_ADD | BPF_X, 0));
+    }
+    program.extend([
+        stmt(BPF_ALU | BPF_MOD | BPF_K, workers),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, worker, 0, 1),
+        // The return value is the number of bytes to keep.
+        stmt(BPF_RET | BPF_K, u32::MAX),
+        stmt(BPF_RET | BPF_K, 0),
+    ]);
+    program
+}
+
+/// Datagrams dropped in the receive buffers of `workers` sockets with
+/// steering filters, whose drop counters sum up to `drop_counters` while
+/// `delivered` datagrams were received from them in total.
+///
+/// The kernel counts the datagrams rejected by the filter of a socket as its
+/// drops. Every datagram reaches all sockets and is either delivered to or
+/// dropped by the one which accepts it, so it is counted by all others.
+pub fn buffer_drops(drop_counters: u64, delivered: u64, workers: u64) -> u64 {
+    // The counters are only read with received datagrams, so they may miss
+    // the latest rejected ones.
+    drop_counters.saturating_sub((workers - 1) * delivered) / workers
+}
+
+/// Attach a classic BPF socket filter.
+pub fn attach_filter(socket: RawFd, program: &[sock_filter]) -> nix::Result<()> {
+    let fprog = sock_fprog {
+        len: program.len() as libc::c_ushort,
+        // The kernel copies the program and does not modify it.
+        filter: program.as_ptr() as *mut sock_filter,
+    };
+    set_option(socket, libc::SOL_SOCKET, SO_ATTACH_FILTER, &fprog)
+}
+
+/// Restrict the calling thread to `cpu`.
+pub fn pin_thread(cpu: usize) -> nix::Result<()> {
+    let mut cpus = CpuSet::new();
+    cpus.set(cpu)?;
+    // Pid 0 is the calling thread.
+    sched_setaffinity(Pid::from_raw(0), &cpus)
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_drops_without_filtered_datagrams() {
+        // One socket counts only real drops.
+        assert_eq!(buffer_drops(7, 100, 1), 7);
+        // 100 datagrams, 50 for each of two workers, without drops.
+        assert_eq!(buffer_drops(50 + 50, 100, 2), 0);
+        // 10 of the datagrams for the first worker were dropped.
+        assert_eq!(buffer_drops((50 + 10) + 50, 90, 2), 10);
+        // 3 workers, 30 datagrams each, 6 dropped at the second one.
+        assert_eq!(buffer_drops(60 + (60 + 6) + 60, 84, 3), 6);
+    }
+}
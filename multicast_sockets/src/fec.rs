@@ -0,0 +1,595 @@
+//! Forward error correction with XOR parity over interleaved blocks.
+//!
+//! The sender groups `block_len * depth` consecutive packets of a stream
+//! into a span and emits one parity packet per column of the span. Column
+//! `j` covers the packets `first + j + k * depth` for `k < block_len`, so a
+//! burst of up to `depth` consecutive losses hits every column only once and
+//! can be fully recovered.
+//!
+//! A parity packet has [`Flags::PARITY`] set, the sequence number of the
+//! first covered packet and the XOR of the covered send timestamps in its
+//! header. Its payload is, in network byte order:
+//!
+//! ```text
+//! +---------------+---------------+-------------------------------+
+//! |   block len   |     depth     |     XOR of payload lengths    |
+//! +---------------+---------------+-------------------------------+
+//! |  XOR of the payloads, zero padded to the longest ...
+//! +---------------------------------------------------------------+
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use crate::packet::{Flags, Header, Packet};
+
+/// Length of the parity payload before the XOR of the payloads.
+const PARITY_HEADER_LEN: usize = 4;
+/// Largest number of packets in a span, bounded by the receive window.
+const MAX_SPAN: u32 = 1024;
+/// Number of recent packets the decoder keeps to reconstruct lost ones.
+const WINDOW: usize = 4 * MAX_SPAN as usize;
+/// Most parity packets waiting for missing packets, which is more than the
+/// spans within [`MAX_SPAN`] of the highest sequence number have.
+const MAX_PENDING: usize = MAX_SPAN as usize;
+
+/// Shape of the parity blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecConfig {
+    /// Packets covered by one parity packet.
+    pub block_len: u8,
+    /// Number of interleaved columns, the longest recoverable loss burst.
+    pub depth: u8,
+}
+
+impl FecConfig {
+    /// Number of data packets after which all parity packets are sent.
+    pub fn span(&self) -> u32 {
+        u32::from(self.block_len) * u32::from(self.depth)
+    }
+}
+
+impl FromStr for FecConfig {
+    type Err = anyhow::Error;
+
+    /// Parse `N` or `NxD` for parity over blocks of `N` packets interleaved
+    /// `D` deep.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (block_len, depth) = s.split_once('x').unwrap_or((s, "1"));
+        let config = Self {
+            block_len: block_len.parse().context("invalid block length")?,
+            depth: depth.parse().context("invalid interleave depth")?,
+        };
+        if config.block_len < 2 || config.depth == 0 {
+            bail!("blocks need at least 2 packets and 1 column");
+        }
+        if config.span() > MAX_SPAN {
+            bail!("blocks may span at most {MAX_SPAN} packets");
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for FecConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.block_len, self.depth)
+    }
+}
+
+/// XOR accumulator of one parity packet.
+#[derive(Debug, Default, Clone)]
+struct Parity {
+    timestamp_xor: u64,
+    length_xor: u16,
+    payload_xor: Vec<u8>,
+}
+
+impl Parity {
+    fn add(&mut self, timestamp_ns: u64, payload: &[u8]) {
+        self.timestamp_xor ^= timestamp_ns;
+        self.length_xor ^= payload.len() as u16;
+        if self.payload_xor.len() < payload.len() {
+            self.payload_xor.resize(payload.len(), 0);
+        }
+        for (xor, byte) in self.payload_xor.iter_mut().zip(payload) {
+            *xor ^= byte;
+        }
+    }
+}
+
+/// Generates the parity packets of a stream on the sender side.
+#[derive(Debug, Clone)]
+pub struct FecEncoder {
+    config: FecConfig,
+    /// Sequence number of the first packet of the current span.
+    first: Option<u32>,
+    columns: Vec<Parity>,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            first: None,
+            columns: vec![Parity::default(); usize::from(config.depth)],
+        }
+    }
+
+    /// Add a sent data packet. After the last packet of a span, `emit` is
+    /// called with the header and payload of every parity packet.
+    pub fn push(&mut self, header: &Header, payload: &[u8], mut emit: impl FnMut(&Header, &[u8])) {
+        let first = *self.first.get_or_insert(header.sequence);
+        let index = header.sequence.wrapping_sub(first);
+        if index >= self.config.span() {
+            // The sequence numbers jumped, start a new span.
+            self.reset();
+            return self.push(header, payload, emit);
+        }
+
+        let column = (index % u32::from(self.config.depth)) as usize;
+        self.columns[column].add(header.timestamp_ns, payload);
+        if index + 1 < self.config.span() {
+            return;
+        }
+
+        let mut parity_payload = Vec::new();
+        for (column, parity) in self.columns.iter().enumerate() {
+            let mut flags = header.flags;
+            flags.set(Flags::PARITY, true);
+            let parity_header = Header {
+                flags,
+                stream_id: header.stream_id,
+                sequence: first.wrapping_add(column as u32),
+                timestamp_ns: parity.timestamp_xor,
+            };
+
+            parity_payload.clear();
+            parity_payload.extend_from_slice(&[self.config.block_len, self.config.depth]);
+            parity_payload.extend_from_slice(&parity.length_xor.to_be_bytes());
+            parity_payload.extend_from_slice(&parity.payload_xor);
+            emit(&parity_header, &parity_payload);
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.first = None;
+        self.columns.fill(Parity::default());
+    }
+}
+
+/// A data packet reconstructed from parity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovered {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+/// Counters collected by a [`FecDecoder`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FecStats {
+    /// Received parity packets.
+    pub parity: u64,
+    /// Lost packets reconstructed from parity.
+    pub recovered: u64,
+    /// Lost packets covered by a parity packet which missed more than one.
+    pub unrecoverable: u64,
+}
+
+impl fmt::Display for FecStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FEC parity {}, recovered {}, unrecoverable {}",
+            self.parity, self.recovered, self.unrecoverable
+        )
+    }
+}
+
+/// A received parity packet which is still missing more than one packet.
+#[derive(Debug, Clone)]
+struct PendingParity {
+    header: Header,
+    block_len: u8,
+    depth: u8,
+    parity: Parity,
+}
+
+impl PendingParity {
+    fn covered(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..u32::from(self.block_len))
+            .map(|k| self.header.sequence.wrapping_add(k * u32::from(self.depth)))
+    }
+
+    fn last(&self) -> u32 {
+        self.covered().last().unwrap_or(self.header.sequence)
+    }
+
+    /// Number of covered packets which were not received.
+    fn missing(&self, received: &HashMap<u32, (u64, Vec<u8>)>) -> u64 {
+        self.covered()
+            .filter(|seq| !received.contains_key(seq))
+            .count() as u64
+    }
+}
+
+/// Reconstructs lost packets of a stream from parity packets.
+#[derive(Debug, Default, Clone)]
+pub struct FecDecoder {
+    /// Send timestamps and payloads of recently received packets.
+    received: HashMap<u32, (u64, Vec<u8>)>,
+    /// Order in which `received` was filled, oldest first.
+    order: VecDeque<u32>,
+    highest: Option<u32>,
+    pending: Vec<PendingParity>,
+    stats: FecStats,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process a data or parity packet. Returns the packets which could be
+    /// reconstructed with it.
+    pub fn push(&mut self, packet: &Packet) -> Vec<Recovered> {
+        if !packet.header.flags.contains(Flags::PARITY) {
+            let sequence = packet.header.sequence;
+            self.insert(
+                sequence,
+                packet.header.timestamp_ns,
+                packet.payload.to_vec(),
+            );
+            self.advance(sequence);
+            return self.retry_pending(sequence);
+        }
+
+        self.stats.parity += 1;
+        let Some(pending) = parse_parity(packet) else {
+            return Vec::new();
+        };
+        self.advance(pending.last());
+        match self.try_recover(&pending) {
+            Some(recovered) => recovered.into_iter().collect(),
+            None => {
+                self.add_pending(pending);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Give up on the parity packets still missing more than one packet,
+    /// e.g. at the end of the stream, and count those as unrecoverable.
+    pub fn finish(&mut self) {
+        for pending in std::mem::take(&mut self.pending) {
+            self.stats.unrecoverable += pending.missing(&self.received);
+        }
+    }
+
+    /// Current statistics of this stream.
+    pub fn stats(&self) -> FecStats {
+        self.stats
+    }
+
+    fn insert(&mut self, sequence: u32, timestamp_ns: u64, payload: Vec<u8>) {
+        if self
+            .received
+            .insert(sequence, (timestamp_ns, payload))
+            .is_none()
+        {
+            self.order.push_back(sequence);
+        }
+        while self.order.len() > WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+    }
+
+    /// Move the highest sequence number forward and give up on parity whose
+    /// missing packets will not arrive anymore.
+    fn advance(&mut self, sequence: u32) {
+        let highest = match self.highest {
+            Some(highest) if (sequence.wrapping_sub(highest) as i32) <= 0 => highest,
+            _ => sequence,
+        };
+        self.highest = Some(highest);
+
+        let received = &self.received;
+        let stats = &mut self.stats;
+        self.pending.retain(|pending| {
+            let outdated = highest.wrapping_sub(pending.last()) > MAX_SPAN;
+            if outdated {
+                stats.unrecoverable += pending.missing(received);
+            }
+            !outdated
+        });
+    }
+
+    /// Keep `pending` until its missing packets arrive, unless the same
+    /// parity is kept already. Beyond [`MAX_PENDING`], the parity furthest
+    /// behind is given up on.
+    fn add_pending(&mut self, pending: PendingParity) {
+        let sequence = pending.header.sequence;
+        if self
+            .pending
+            .iter()
+            .any(|known| known.header.sequence == sequence)
+        {
+            return;
+        }
+        if self.pending.len() == MAX_PENDING {
+            let highest = self.highest.unwrap_or(sequence);
+            let furthest = (0..self.pending.len())
+                .max_by_key(|&idx| highest.wrapping_sub(self.pending[idx].last()))
+                .expect("pending parity");
+            let given_up = self.pending.swap_remove(furthest);
+            self.stats.unrecoverable += given_up.missing(&self.received);
+        }
+        self.pending.push(pending);
+    }
+
+    fn retry_pending(&mut self, sequence: u32) -> Vec<Recovered> {
+        let mut recovered = Vec::new();
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            if !self.pending[idx].covered().any(|seq| seq == sequence) {
+                idx += 1;
+                continue;
+            }
+            let pending = self.pending[idx].clone();
+            match self.try_recover(&pending) {
+                Some(packet) => {
+                    recovered.extend(packet);
+                    self.pending.swap_remove(idx);
+                }
+                None => idx += 1,
+            }
+        }
+        recovered
+    }
+
+    /// Reconstruct the only missing packet of a parity packet. Returns
+    /// `None` if more than one is missing, `Some(None)` if none is.
+    fn try_recover(&mut self, pending: &PendingParity) -> Option<Option<Recovered>> {
+        let mut missing = pending
+            .covered()
+            .filter(|seq| !self.received.contains_key(seq));
+        let Some(sequence) = missing.next() else {
+            return Some(None);
+        };
+        if missing.next().is_some() {
+            return None;
+        }
+
+        let mut parity = pending.parity.clone();
+        for seq in pending.covered().filter(|&seq| seq != sequence) {
+            let (timestamp_ns, payload) = &self.received[&seq];
+            parity.add(*timestamp_ns, payload);
+        }
+        let payload_len = usize::from(parity.length_xor);
+        if payload_len > parity.payload_xor.len() {
+            // Corrupted parity, or a covered packet was not the original.
+            return Some(None);
+        }
+        parity.payload_xor.truncate(payload_len);
+
+        let mut flags = pending.header.flags;
+        flags.set(Flags::PARITY, false);
+        let header = Header {
+            flags,
+            stream_id: pending.header.stream_id,
+            sequence,
+            timestamp_ns: parity.timestamp_xor,
+        };
+        self.insert(sequence, header.timestamp_ns, parity.payload_xor.clone());
+        self.stats.recovered += 1;
+
+        Some(Some(Recovered {
+            header,
+            payload: parity.payload_xor,
+        }))
+    }
+}
+
+fn parse_parity(packet: &Packet) -> Option<PendingParity> {
+    let (fec_header, payload_xor) = packet.payload.split_at_checked(PARITY_HEADER_LEN)?;
+    let [block_len, depth, length_xor @ ..] = fec_header else {
+        return None;
+    };
+    let span = u32::from(*block_len) * u32::from(*depth);
+    if span == 0 || span > MAX_SPAN {
+        return None;
+    }
+
+    Some(PendingParity {
+        header: packet.header,
+        block_len: *block_len,
+        depth: *depth,
+        parity: Parity {
+            timestamp_xor: packet.header.timestamp_ns,
+            length_xor: u16::from_be_bytes(length_xor.try_into().ok()?),
+            payload_xor: payload_xor.to_vec(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode `count` packets with parity and return them in send order.
+    fn send(config: FecConfig, count: u32) -> Vec<(Header, Vec<u8>)> {
+        let mut encoder = FecEncoder::new(config);
+        let mut packets = Vec::new();
+        for sequence in 0..count {
+            let header = Header {
+                flags: Flags::CRC,
+                stream_id: 7,
+                sequence,
+                timestamp_ns: 1_000_000 + u64::from(sequence) * 37,
+            };
+            // Payloads of different lengths.
+            let payload = vec![sequence as u8; sequence as usize % 5];
+            packets.push((header, payload.clone()));
+            encoder.push(&header, &payload, |header, payload| {
+                packets.push((*header, payload.to_vec()));
+            });
+        }
+        packets
+    }
+
+    fn receive(
+        decoder: &mut FecDecoder,
+        packets: &[(Header, Vec<u8>)],
+        lost: &[u32],
+    ) -> Vec<Recovered> {
+        let mut recovered = Vec::new();
+        for (header, payload) in packets {
+            let is_parity = header.flags.contains(Flags::PARITY);
+            if !is_parity && lost.contains(&header.sequence) {
+                continue;
+            }
+            recovered.extend(decoder.push(&Packet {
+                header: *header,
+                payload,
+            }));
+        }
+        recovered
+    }
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(
+            "8".parse::<FecConfig>().unwrap(),
+            FecConfig {
+                block_len: 8,
+                depth: 1
+            }
+        );
+        assert_eq!(
+            "4x3".parse::<FecConfig>().unwrap(),
+            FecConfig {
+                block_len: 4,
+                depth: 3
+            }
+        );
+        assert!("1".parse::<FecConfig>().is_err());
+        assert!("4x0".parse::<FecConfig>().is_err());
+        assert!("255x255".parse::<FecConfig>().is_err());
+    }
+
+    #[test]
+    fn recovers_burst_within_depth() {
+        let config = "4x3".parse().unwrap();
+        let packets = send(config, 24);
+        assert_eq!(packets.len(), 24 + 2 * 3);
+
+        let mut decoder = FecDecoder::new();
+        let recovered = receive(&mut decoder, &packets, &[4, 5, 6, 13]);
+
+        assert_eq!(recovered.len(), 4);
+        for recovered in recovered {
+            let (header, payload) = packets
+                .iter()
+                .find(|(header, _)| {
+                    header.sequence == recovered.header.sequence
+                        && !header.flags.contains(Flags::PARITY)
+                })
+                .unwrap();
+            assert_eq!(recovered.header, *header);
+            assert_eq!(recovered.payload, *payload);
+        }
+        assert_eq!(
+            decoder.stats(),
+            FecStats {
+                parity: 6,
+                recovered: 4,
+                unrecoverable: 0
+            }
+        );
+    }
+
+    #[test]
+    fn reports_unrecoverable_losses() {
+        let config = "4x1".parse().unwrap();
+        let mut packets = send(config, 8);
+        // Two losses in the first block, then enough packets to expire it.
+        packets.extend(send(config, 2000).into_iter().skip(8 + 2));
+
+        let mut decoder = FecDecoder::new();
+        let recovered = receive(&mut decoder, &packets, &[1, 2, 5]);
+
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].header.sequence, 5);
+        assert_eq!(decoder.stats().recovered, 1);
+        assert_eq!(decoder.stats().unrecoverable, 2);
+    }
+
+    #[test]
+    fn keeps_parity_once_and_gives_up_at_the_end() {
+        let config = "4x1".parse().unwrap();
+        let mut packets = send(config, 4);
+        let parity = packets.last().unwrap().clone();
+        packets.extend([parity.clone(), parity]);
+
+        let mut decoder = FecDecoder::new();
+        assert!(receive(&mut decoder, &packets, &[1, 2]).is_empty());
+        assert_eq!(decoder.pending.len(), 1);
+        assert_eq!(decoder.stats().unrecoverable, 0);
+
+        decoder.finish();
+        assert!(decoder.pending.is_empty());
+        assert_eq!(
+            decoder.stats(),
+            FecStats {
+                parity: 3,
+                recovered: 0,
+                unrecoverable: 2
+            }
+        );
+    }
+
+    #[test]
+    fn bounds_pending_parity() {
+        let mut decoder = FecDecoder::new();
+        // Parity over pairs of packets which never arrive.
+        let payload = [2, 1, 0, 0];
+        for sequence in 0..3 * MAX_SPAN {
+            let header = Header {
+                flags: Flags::PARITY,
+                sequence,
+                ..Default::default()
+            };
+            decoder.push(&Packet {
+                header,
+                payload: &payload,
+            });
+            assert!(decoder.pending.len() <= MAX_PENDING);
+        }
+        decoder.finish();
+        assert_eq!(decoder.stats().unrecoverable, 2 * u64::from(3 * MAX_SPAN));
+    }
+
+    #[test]
+    fn recovers_when_data_arrives_after_parity() {
+        let config = "3x1".parse().unwrap();
+        let packets = send(config, 3);
+        let (data, parity) = packets.split_at(3);
+
+        let mut decoder = FecDecoder::new();
+        // Only the parity and one data packet, then a reordered one.
+        assert!(receive(&mut decoder, &data[..1], &[]).is_empty());
+        assert!(receive(&mut decoder, parity, &[]).is_empty());
+        let recovered = receive(&mut decoder, &data[2..], &[]);
+
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].header, data[1].0);
+        assert_eq!(recovered[0].payload, data[1].1);
+    }
+}
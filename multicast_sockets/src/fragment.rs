@@ -0,0 +1,647 @@
+//! Fragmentation of messages larger than a datagram and their reassembly.
+//!
+//! The sender splits a message into chunks which fit the MTU and sends every
+//! chunk as the payload of its own packet with [`Flags::FRAGMENT`] set, so
+//! sequence tracking, FEC and NACKs work on the fragments. The chunk is
+//! preceded by a fragment header, in network byte order:
+//!
+//! ```text
+//! +---------------------------------------------------------------+
+//! |                          message id                           |
+//! +-------------------------------+-------------------------------+
+//! |         fragment index        |        fragment count         |
+//! +-------------------------------+-------------------------------+
+//! |                     message length in bytes                   |
+//! +---------------------------------------------------------------+
+//! |  chunk ...
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! All fragments but the last carry chunks of the same length, so every
+//! fragment can be placed on its own: fragment `i` starts at `i` times its
+//! length, the last one ends at the end of the message.
+//!
+//! [`Flags::FRAGMENT`]: crate::packet::Flags::FRAGMENT
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+
+use crate::packet::Header;
+
+/// Length of the fragment header before the chunk.
+pub const FRAGMENT_HEADER_LEN: usize = 12;
+/// Length of the IPv4 and IPv6 headers without options plus the UDP header.
+const IPV4_UDP_HEADER_LEN: usize = 20 + 8;
+const IPV6_UDP_HEADER_LEN: usize = 40 + 8;
+/// Default time after which incomplete messages are dropped.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Default memory limit for incomplete messages per stream.
+pub const DEFAULT_MEMORY_LIMIT: usize = 16 << 20;
+/// Number of completed message ids remembered to recognize late duplicates.
+const COMPLETED_HISTORY: usize = 256;
+
+/// Header in front of every chunk of a message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub message_id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub message_len: u32,
+}
+
+/// Reasons why a fragment can not be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// The payload is shorter than the fragment header.
+    TooShort(usize),
+    /// The index is not below the fragment count.
+    InvalidIndex { index: u16, count: u16 },
+    /// The chunk does not fit the message or the other fragments.
+    Inconsistent { message_id: u32, index: u16 },
+    /// The message and its fragments need more than the memory limit of the
+    /// reassembler.
+    TooLarge { message_len: u32, limit: usize },
+    /// The message needs more than `u16::MAX` fragments.
+    TooManyFragments(usize),
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::TooShort(len) => write!(f, "fragment of {len} bytes too short"),
+            FragmentError::InvalidIndex { index, count } => {
+                write!(f, "fragment index {index} of {count} fragments")
+            }
+            FragmentError::Inconsistent { message_id, index } => {
+                write!(f, "fragment {index} does not fit message {message_id}")
+            }
+            FragmentError::TooLarge { message_len, limit } => {
+                write!(
+                    f,
+                    "message of {message_len} bytes exceeds the limit of {limit} bytes"
+                )
+            }
+            FragmentError::TooManyFragments(count) => {
+                write!(
+                    f,
+                    "message needs {count} fragments, at most 65535 are possible"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
+impl FragmentHeader {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.put_u32(self.message_id);
+        out.put_u16(self.index);
+        out.put_u16(self.count);
+        out.put_u32(self.message_len);
+    }
+
+    /// Decode the header and return the chunk after it.
+    pub fn decode(payload: &[u8]) -> Result<(Self, &[u8]), FragmentError> {
+        if payload.len() < FRAGMENT_HEADER_LEN {
+            return Err(FragmentError::TooShort(payload.len()));
+        }
+        let mut buf = payload;
+        let header = Self {
+            message_id: buf.get_u32(),
+            index: buf.get_u16(),
+            count: buf.get_u16(),
+            message_len: buf.get_u32(),
+        };
+        if header.index >= header.count {
+            return Err(FragmentError::InvalidIndex {
+                index: header.index,
+                count: header.count,
+            });
+        }
+        Ok((header, buf))
+    }
+}
+
+/// Longest chunk per fragment so that the datagram with `header` fits into
+/// `mtu`.
+pub fn max_chunk_len(mtu: u32, ipv4: bool, header: &Header) -> usize {
+    let ip_udp_len = match ipv4 {
+        true => IPV4_UDP_HEADER_LEN,
+        false => IPV6_UDP_HEADER_LEN,
+    };
+    (mtu as usize)
+        .saturating_sub(ip_udp_len + header.encoded_len(0) + FRAGMENT_HEADER_LEN)
+        .max(1)
+}
+
+/// Splits messages into fragment payloads with consecutive message ids.
+#[derive(Debug, Clone)]
+pub struct Fragmenter {
+    chunk_len: usize,
+    next_message_id: u32,
+    fragment: Vec<u8>,
+}
+
+impl Fragmenter {
+    /// Split into chunks of at most `chunk_len` bytes, see [`max_chunk_len`].
+    pub fn new(chunk_len: usize) -> Self {
+        Self {
+            chunk_len: chunk_len.max(1),
+            next_message_id: 0,
+            fragment: Vec::new(),
+        }
+    }
+
+    /// Number of fragments of a message of `message_len` bytes.
+    pub fn fragment_count(&self, message_len: usize) -> usize {
+        message_len.div_ceil(self.chunk_len).max(1)
+    }
+
+    /// Emit the fragment payloads of `message` in order.
+    pub fn split(
+        &mut self,
+        message: &[u8],
+        mut emit: impl FnMut(&[u8]),
+    ) -> Result<(), FragmentError> {
+        let count = self.fragment_count(message.len());
+        let count = u16::try_from(count).map_err(|_| FragmentError::TooManyFragments(count))?;
+        let message_len = u32::try_from(message.len())
+            .map_err(|_| FragmentError::TooManyFragments(usize::MAX))?;
+
+        let mut header = FragmentHeader {
+            message_id: self.next_message_id,
+            index: 0,
+            count,
+            message_len,
+        };
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        // An empty message still needs one fragment.
+        let empty = message.is_empty().then_some(&[][..]);
+        for chunk in message.chunks(self.chunk_len).chain(empty) {
+            self.fragment.clear();
+            header.encode(&mut self.fragment);
+            self.fragment.extend_from_slice(chunk);
+            emit(&self.fragment);
+            header.index = header.index.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+/// Counters of a [`Reassembler`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Completely reassembled messages.
+    pub messages: u64,
+    /// Fragments placed into a message.
+    pub fragments: u64,
+    /// Fragments which were already placed.
+    pub duplicates: u64,
+    /// Incomplete messages dropped after the timeout.
+    pub timed_out: u64,
+    /// Incomplete messages dropped to stay within the memory limit.
+    pub evicted: u64,
+    /// Invalid fragments and messages larger than the memory limit.
+    pub rejected: u64,
+}
+
+impl fmt::Display for ReassemblyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "messages {}, fragments {}, duplicates {}, timed out {}, evicted {}, rejected {}",
+            self.messages,
+            self.fragments,
+            self.duplicates,
+            self.timed_out,
+            self.evicted,
+            self.rejected
+        )
+    }
+}
+
+/// A message of which some fragments arrived.
+#[derive(Debug, Clone)]
+struct Partial {
+    data: Vec<u8>,
+    count: u16,
+    received: Vec<bool>,
+    missing: u16,
+    /// Length of all chunks but the last, once one of them arrived.
+    chunk_len: Option<usize>,
+    /// Length of the last chunk, once it arrived.
+    last_len: Option<usize>,
+    started: Instant,
+}
+
+impl Partial {
+    /// Bytes allocated for the message and the received fragments.
+    fn size(&self) -> usize {
+        self.data.len() + self.received.len()
+    }
+}
+
+/// Reassembles the messages of one stream from their fragments.
+#[derive(Debug, Clone)]
+pub struct Reassembler {
+    timeout: Duration,
+    memory_limit: usize,
+    partials: HashMap<u32, Partial>,
+    /// Bytes allocated for partial messages.
+    memory: usize,
+    completed: VecDeque<u32>,
+    stats: ReassemblyStats,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT, DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+impl Reassembler {
+    /// Drop incomplete messages after `timeout` and keep at most
+    /// `memory_limit` bytes of them, dropping the oldest first.
+    pub fn new(timeout: Duration, memory_limit: usize) -> Self {
+        Self {
+            timeout,
+            memory_limit,
+            partials: HashMap::new(),
+            memory: 0,
+            completed: VecDeque::with_capacity(COMPLETED_HISTORY),
+            stats: ReassemblyStats::default(),
+        }
+    }
+
+    /// Place a fragment payload. Returns the message once it is complete.
+    pub fn push(&mut self, payload: &[u8], now: Instant) -> Result<Option<Vec<u8>>, FragmentError> {
+        self.expire(now);
+        let result = self.place(payload, now);
+        if result.is_err() {
+            self.stats.rejected += 1;
+        }
+        result
+    }
+
+    fn place(&mut self, payload: &[u8], now: Instant) -> Result<Option<Vec<u8>>, FragmentError> {
+        let (header, chunk) = FragmentHeader::decode(payload)?;
+        let inconsistent = FragmentError::Inconsistent {
+            message_id: header.message_id,
+            index: header.index,
+        };
+        let message_len = header.message_len as usize;
+
+        if self.completed.contains(&header.message_id) {
+            self.stats.duplicates += 1;
+            return Ok(None);
+        }
+
+        let last = header.index + 1 == header.count;
+        let offset = match last {
+            true => message_len.checked_sub(chunk.len()).ok_or(inconsistent)?,
+            false => usize::from(header.index) * chunk.len(),
+        };
+        if offset + chunk.len() > message_len || (header.count == 1 && offset != 0) {
+            return Err(inconsistent);
+        }
+        // Every fragment but the one of an empty message carries a byte.
+        let count = usize::from(header.count);
+        if count > message_len.max(1) {
+            return Err(inconsistent);
+        }
+
+        if !self.partials.contains_key(&header.message_id) {
+            let size = message_len + count;
+            if size > self.memory_limit {
+                return Err(FragmentError::TooLarge {
+                    message_len: header.message_len,
+                    limit: self.memory_limit,
+                });
+            }
+            while self.memory + size > self.memory_limit {
+                self.evict_oldest();
+            }
+            self.memory += size;
+            self.partials.insert(
+                header.message_id,
+                Partial {
+                    data: vec![0; message_len],
+                    count: header.count,
+                    received: vec![false; count],
+                    missing: header.count,
+                    chunk_len: None,
+                    last_len: None,
+                    started: now,
+                },
+            );
+        }
+        let partial = self.partials.get_mut(&header.message_id).unwrap();
+
+        if partial.count != header.count || partial.data.len() != message_len {
+            return Err(inconsistent);
+        }
+        let chunk_len = match (last, partial.chunk_len) {
+            (false, None) => {
+                // The first other chunk fixes the length of all but the last
+                // one, which has to be placed after them, and may have
+                // arrived already.
+                let before_last = (count - 1) * chunk.len();
+                let fits = before_last < message_len && message_len <= before_last + chunk.len();
+                if !fits
+                    || partial
+                        .last_len
+                        .is_some_and(|last_len| message_len - last_len != before_last)
+                {
+                    return Err(inconsistent);
+                }
+                Some(*partial.chunk_len.insert(chunk.len()))
+            }
+            (_, chunk_len) => chunk_len,
+        };
+        let misplaced = match (last, chunk_len) {
+            (false, Some(chunk_len)) => chunk.len() != chunk_len,
+            (true, Some(chunk_len)) => offset != usize::from(header.index) * chunk_len,
+            (true, None) => false,
+            (false, None) => unreachable!(),
+        };
+        if misplaced {
+            return Err(inconsistent);
+        }
+
+        let received = &mut partial.received[usize::from(header.index)];
+        if *received {
+            self.stats.duplicates += 1;
+            return Ok(None);
+        }
+        *received = true;
+        if last {
+            partial.last_len = Some(chunk.len());
+        }
+        partial.missing -= 1;
+        partial.data[offset..offset + chunk.len()].copy_from_slice(chunk);
+        self.stats.fragments += 1;
+
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+        let partial = self.partials.remove(&header.message_id).unwrap();
+        self.memory -= partial.size();
+        if self.completed.len() == COMPLETED_HISTORY {
+            self.completed.pop_front();
+        }
+        self.completed.push_back(header.message_id);
+        self.stats.messages += 1;
+        Ok(Some(partial.data))
+    }
+
+    /// Drop the incomplete messages which are older than the timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let mut expired = 0;
+        self.partials.retain(|_, partial| {
+            let keep = now.saturating_duration_since(partial.started) < timeout;
+            if !keep {
+                expired += partial.size();
+                self.stats.timed_out += 1;
+            }
+            keep
+        });
+        self.memory -= expired;
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .partials
+            .iter()
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(&message_id, _)| message_id);
+        if let Some(partial) = oldest.and_then(|message_id| self.partials.remove(&message_id)) {
+            self.memory -= partial.size();
+            self.stats.evicted += 1;
+        }
+    }
+
+    /// Bytes currently allocated for incomplete messages, including one per
+    /// fragment to track which ones arrived.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Current statistics of this stream.
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(fragmenter: &mut Fragmenter, message: &[u8]) -> Vec<Vec<u8>> {
+        let mut fragments = Vec::new();
+        fragmenter
+            .split(message, |fragment| fragments.push(fragment.to_vec()))
+            .unwrap();
+        fragments
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut fragmenter = Fragmenter::new(4);
+        let message: Vec<u8> = (0..10).collect();
+        let mut fragments = split(&mut fragmenter, &message);
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragmenter.fragment_count(message.len()), 3);
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 1024);
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&fragments[0], now), Ok(None));
+        assert_eq!(reassembler.push(&fragments[0], now), Ok(None));
+        assert_eq!(reassembler.push(&fragments[1], now), Ok(None));
+        assert_eq!(reassembler.memory(), 10 + 3);
+        assert_eq!(reassembler.push(&fragments[2], now), Ok(Some(message)));
+        assert_eq!(reassembler.push(&fragments[2], now), Ok(None));
+        assert_eq!(reassembler.memory(), 0);
+
+        let empty = split(&mut fragmenter, &[]);
+        assert_eq!(empty.len(), 1);
+        assert_eq!(reassembler.push(&empty[0], now), Ok(Some(Vec::new())));
+
+        let stats = reassembler.stats();
+        assert_eq!(stats.messages, 2);
+        assert_eq!(stats.fragments, 4);
+        assert_eq!(stats.duplicates, 2);
+    }
+
+    #[test]
+    fn enforces_timeout_and_memory_limit() {
+        let mut fragmenter = Fragmenter::new(4);
+        let timeout = Duration::from_millis(10);
+        let mut reassembler = Reassembler::new(timeout, 20);
+        let start = Instant::now();
+
+        let first = split(&mut fragmenter, &[1; 12]);
+        let second = split(&mut fragmenter, &[2; 12]);
+        assert_eq!(reassembler.push(&first[0], start), Ok(None));
+        // The second message does not fit next to the first one.
+        assert_eq!(reassembler.push(&second[0], start + timeout / 2), Ok(None));
+        assert_eq!(reassembler.stats().evicted, 1);
+        assert_eq!(reassembler.memory(), 12 + 3);
+
+        reassembler.expire(start + timeout * 2);
+        assert_eq!(reassembler.stats().timed_out, 1);
+        assert_eq!(reassembler.memory(), 0);
+
+        let large = split(&mut fragmenter, &[3; 21]);
+        assert_eq!(
+            reassembler.push(&large[0], start),
+            Err(FragmentError::TooLarge {
+                message_len: 21,
+                limit: 20
+            })
+        );
+        assert_eq!(reassembler.stats().rejected, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_fragments() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 1024);
+        let now = Instant::now();
+        assert_eq!(
+            reassembler.push(&[0; 4], now),
+            Err(FragmentError::TooShort(4))
+        );
+
+        let mut fragment = Vec::new();
+        let header = FragmentHeader {
+            message_id: 1,
+            index: 2,
+            count: 2,
+            message_len: 8,
+        };
+        header.encode(&mut fragment);
+        assert_eq!(
+            reassembler.push(&fragment, now),
+            Err(FragmentError::InvalidIndex { index: 2, count: 2 })
+        );
+
+        // A first chunk of 6 bytes does not leave room for the second.
+        fragment.clear();
+        FragmentHeader { index: 0, ..header }.encode(&mut fragment);
+        fragment.extend_from_slice(&[0; 6]);
+        assert_eq!(reassembler.push(&fragment, now), Ok(None));
+        fragment.clear();
+        FragmentHeader { index: 1, ..header }.encode(&mut fragment);
+        fragment.extend_from_slice(&[0; 4]);
+        assert!(matches!(
+            reassembler.push(&fragment, now),
+            Err(FragmentError::Inconsistent { .. })
+        ));
+    }
+
+    #[test]
+    fn checks_the_last_fragment_when_it_arrives_first() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 1024);
+        let now = Instant::now();
+        let header = FragmentHeader {
+            message_id: 1,
+            index: 1,
+            count: 2,
+            message_len: 10,
+        };
+        let fragment = |header: FragmentHeader, len: usize| {
+            let mut fragment = Vec::new();
+            header.encode(&mut fragment);
+            fragment.extend(std::iter::repeat_n(header.index as u8, len));
+            fragment
+        };
+
+        // A last chunk of 3 bytes leaves a hole after a first one of 6.
+        assert_eq!(reassembler.push(&fragment(header, 3), now), Ok(None));
+        let first = FragmentHeader { index: 0, ..header };
+        assert!(matches!(
+            reassembler.push(&fragment(first, 6), now),
+            Err(FragmentError::Inconsistent { .. })
+        ));
+        // Chunks of 4 bytes can not make up the message with 2 fragments.
+        assert!(matches!(
+            reassembler.push(&fragment(first, 4), now),
+            Err(FragmentError::Inconsistent { .. })
+        ));
+        assert_eq!(
+            reassembler.push(&fragment(first, 7), now),
+            Ok(Some([0, 0, 0, 0, 0, 0, 0, 1, 1, 1].to_vec()))
+        );
+    }
+
+    #[test]
+    fn counts_fragment_tracking_against_the_memory_limit() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 2000);
+        let now = Instant::now();
+
+        // More fragments than bytes would allocate unaccounted memory.
+        let mut fragment = Vec::new();
+        let header = FragmentHeader {
+            message_id: 1,
+            index: 0,
+            count: u16::MAX,
+            message_len: 0,
+        };
+        header.encode(&mut fragment);
+        assert_eq!(
+            reassembler.push(&fragment, now),
+            Err(FragmentError::Inconsistent {
+                message_id: 1,
+                index: 0
+            })
+        );
+        assert_eq!(reassembler.memory(), 0);
+
+        // One byte per fragment is the most allowed.
+        let header = FragmentHeader {
+            count: 600,
+            message_len: 600,
+            ..header
+        };
+        fragment.clear();
+        header.encode(&mut fragment);
+        fragment.push(0);
+        assert_eq!(reassembler.push(&fragment, now), Ok(None));
+        assert_eq!(reassembler.memory(), 1200);
+
+        // The second such message does not fit next to the first one.
+        fragment.clear();
+        FragmentHeader {
+            message_id: 2,
+            ..header
+        }
+        .encode(&mut fragment);
+        fragment.push(0);
+        assert_eq!(reassembler.push(&fragment, now), Ok(None));
+        assert_eq!(reassembler.stats().evicted, 1);
+        assert_eq!(reassembler.memory(), 1200);
+    }
+
+    #[test]
+    fn chunks_fit_the_mtu() {
+        let header = Header::default();
+        let chunk_len = max_chunk_len(1500, true, &header);
+        assert_eq!(
+            IPV4_UDP_HEADER_LEN + header.encoded_len(FRAGMENT_HEADER_LEN + chunk_len),
+            1500
+        );
+        let chunk_len = max_chunk_len(1500, false, &header);
+        assert_eq!(
+            IPV6_UDP_HEADER_LEN + header.encoded_len(FRAGMENT_HEADER_LEN + chunk_len),
+            1500
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Multicast groups joined on the interfaces of this host, as reported by
+//! the kernel in `/proc/net/igmp` and `/proc/net/igmp6`.
+//!
+//! A group is listed once per interface as soon as any socket joined it,
+//! with the number of joins as users. Source-specific joins are listed by
+//! their group. This tells whether a join reached the kernel, which then
+//! reports it to the network with IGMP or MLD.
+
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::membership::Membership;
+
+/// A group joined on an interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinedGroup {
+    pub interface_index: u32,
+    pub interface_name: String,
+    pub group: IpAddr,
+    /// Number of joins of the group on the interface.
+    pub users: u32,
+}
+
+/// All groups joined on any interface, IPv4 first. A missing file, e.g.
+/// `/proc/net/igmp6` with IPv6 disabled, contributes no groups.
+pub fn joined_groups() -> io::Result<Vec<JoinedGroup>> {
+    let mut groups = parse_igmp(&read_optional("/proc/net/igmp")?);
+    groups.extend(parse_igmp6(&read_optional("/proc/net/igmp6")?));
+    Ok(groups)
+}
+
+/// The memberships whose group is not joined on their interface.
+pub fn missing<'a>(memberships: &'a [Membership], joined: &[JoinedGroup]) -> Vec<&'a Membership> {
+    memberships
+        .iter()
+        .filter(|membership| {
+            !joined.iter().any(|joined| {
+                joined.group == membership.group
+                    && joined.interface_index == membership.interface_index
+            })
+        })
+        .collect()
+}
+
+/// Parse `/proc/net/igmp`, which lists every interface followed by its
+/// groups, separated by tabs:
+///
+/// ```text
+/// Idx Device    : Count Querier   Group    Users Timer    Reporter
+/// 1   lo        :     1      V3
+///                                 010000E0     1 0:00000000       0
+/// ```
+pub fn parse_igmp(content: &str) -> Vec<JoinedGroup> {
+    let mut groups = Vec::new();
+    let mut interface = None;
+
+    for line in content.lines().skip(1) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if !line.starts_with(char::is_whitespace) {
+            // Names of 10 or more characters are followed by the colon.
+            interface = match fields[..] {
+                [index, name, ..] => index
+                    .parse()
+                    .ok()
+                    .map(|index: u32| (index, name.trim_end_matches(':').to_owned())),
+                _ => None,
+            };
+            continue;
+        }
+
+        let Some((interface_index, interface_name)) = &interface else {
+            continue;
+        };
+        if let [group, users, ..] = fields[..] {
+            // The group is printed as the hexadecimal value of the address in
+            // network byte order read as a native integer.
+            let (Ok(group), Ok(users)) = (u32::from_str_radix(group, 16), users.parse()) else {
+                continue;
+            };
+            groups.push(JoinedGroup {
+                interface_index: *interface_index,
+                interface_name: interface_name.clone(),
+                group: IpAddr::V4(Ipv4Addr::from(group.to_ne_bytes())),
+                users,
+            });
+        }
+    }
+
+    groups
+}
+
+/// Parse `/proc/net/igmp6`, which lists one group per line:
+///
+/// ```text
+/// 1    lo              ff020000000000000000000000000001     1 0000000C 0
+/// ```
+pub fn parse_igmp6(content: &str) -> Vec<JoinedGroup> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [index, name, group, users, ..] = fields[..] else {
+                return None;
+            };
+            Some(JoinedGroup {
+                interface_index: index.parse().ok()?,
+                interface_name: name.to_owned(),
+                group: IpAddr::V6(Ipv6Addr::from(u128::from_str_radix(group, 16).ok()?)),
+                users: users.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Print the joined groups grouped by interface.
+pub fn print_joined_groups() -> io::Result<()> {
+    let mut groups = joined_groups()?;
+    // Stable, so IPv4 groups stay first on every interface.
+    groups.sort_by_key(|group| group.interface_index);
+
+    let mut interface = None;
+    for group in groups {
+        if interface != Some(group.interface_index) {
+            interface = Some(group.interface_index);
+            println!("{}: {}", group.interface_index, group.interface_name);
+        }
+        println!("    {} users {}", group.group, group.users);
+    }
+    Ok(())
+}
+
+fn read_optional(path: &str) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IGMP: &str = "\
+Idx\tDevice    : Count Querier\tGroup    Users Timer\tReporter
+1\tlo        :     1      V3
+\t\t\t\t010000E0     1 0:00000000\t\t0
+4\teth0      :     2      V3
+\t\t\t\t01FFFFEF     2 0:00000000\t\t0
+\t\t\t\t010000E0     1 0:00000000\t\t0
+5\tverylongname0:     1      V3
+\t\t\t\t010000E0     1 0:00000000\t\t0
+";
+
+    const IGMP6: &str = "\
+1    lo              ff010000000000000000000000000001     1 00000008 0
+4    eth0            ff14000000000000000000000000001a     3 00000004 0
+";
+
+    fn group(interface_index: u32, name: &str, group: &str, users: u32) -> JoinedGroup {
+        JoinedGroup {
+            interface_index,
+            interface_name: name.to_owned(),
+            group: group.parse().unwrap(),
+            users,
+        }
+    }
+
+    #[test]
+    fn parses_igmp() {
+        // The hexadecimal values are of a little endian host.
+        if cfg!(target_endian = "little") {
+            assert_eq!(
+                parse_igmp(IGMP),
+                [
+                    group(1, "lo", "224.0.0.1", 1),
+                    group(4, "eth0", "239.255.255.1", 2),
+                    group(4, "eth0", "224.0.0.1", 1),
+                    group(5, "verylongname0", "224.0.0.1", 1),
+                ]
+            );
+        }
+        assert!(parse_igmp("").is_empty());
+    }
+
+    #[test]
+    fn parses_igmp6() {
+        assert_eq!(
+            parse_igmp6(IGMP6),
+            [
+                group(1, "lo", "ff01::1", 1),
+                group(4, "eth0", "ff14::1a", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_missing_memberships() {
+        let joined = parse_igmp6(IGMP6);
+        let group = "ff14::1a".parse().unwrap();
+        let memberships = [
+            Membership::any_source(group, 4),
+            Membership::source_specific(group, "fd00::1".parse().unwrap(), 4),
+            Membership::any_source(group, 1),
+            Membership::any_source("ff14::1b".parse().unwrap(), 4),
+        ];
+        assert_eq!(
+            missing(&memberships, &joined),
+            [&memberships[2], &memberships[3]]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Discovery and selection of multicast capable network interfaces.
+
+use std::{fmt, fs, net::IpAddr, str::FromStr};
+
+use pnet_datalink::NetworkInterface;
+
+/// Route flags of `/proc/net/route` and `/proc/net/ipv6_route`.
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+/// How to select the interface to send or join on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceSelector {
+    Name(String),
+    Index(u32),
+    /// The interface which has this address assigned.
+    Address(IpAddr),
+    /// The interface of the IPv4 or IPv6 default route.
+    DefaultRoute,
+}
+
+impl FromStr for InterfaceSelector {
+    type Err = std::convert::Infallible;
+
+    /// Parse `"default"`, an interface index, an address or else a name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "default" {
+            return Ok(Self::DefaultRoute);
+        }
+        if let Ok(index) = s.parse() {
+            return Ok(Self::Index(index));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::Address(addr));
+        }
+        Ok(Self::Name(s.to_owned()))
+    }
+}
+
+impl fmt::Display for InterfaceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "name {name}"),
+            Self::Index(index) => write!(f, "index {index}"),
+            Self::Address(addr) => write!(f, "address {addr}"),
+            Self::DefaultRoute => write!(f, "default route"),
+        }
+    }
+}
+
+/// No multicast capable interface matched an [`InterfaceSelector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceNotFound {
+    pub selector: InterfaceSelector,
+    /// Names of all multicast capable interfaces.
+    pub candidates: Vec<String>,
+}
+
+impl fmt::Display for InterfaceNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no multicast capable interface with {}", self.selector)?;
+        match self.candidates.is_empty() {
+            true => write!(f, ", there are no multicast capable interfaces"),
+            false => write!(f, ", candidates are {}", self.candidates.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for InterfaceNotFound {}
+
+/// All interfaces with the `MULTICAST` flag.
+pub fn multicast_interfaces() -> Vec<NetworkInterface> {
+    pnet_datalink::interfaces()
+        .into_iter()
+        .filter(NetworkInterface::is_multicast)
+        .collect()
+}
+
+/// Find the multicast capable interface matching `selector`.
+///
+/// A loopback interface has no `MULTICAST` flag, but Linux delivers IPv4
+/// multicast sent on it to the receivers on the same host, so it is found
+/// when selected explicitly by name, index or address.
+pub fn select_interface(
+    selector: &InterfaceSelector,
+) -> Result<NetworkInterface, InterfaceNotFound> {
+    let default_routes = match selector {
+        InterfaceSelector::DefaultRoute => default_route_interfaces(),
+        _ => Vec::new(),
+    };
+
+    let found = pnet_datalink::interfaces()
+        .into_iter()
+        .filter(|interface| {
+            interface.is_multicast()
+                || interface.is_loopback() && *selector != InterfaceSelector::DefaultRoute
+        })
+        .find(|interface| match selector {
+            InterfaceSelector::Name(name) => interface.name == *name,
+            InterfaceSelector::Index(index) => interface.index == *index,
+            InterfaceSelector::Address(addr) => interface.ips.iter().any(|ip| ip.ip() == *addr),
+            InterfaceSelector::DefaultRoute => default_routes.contains(&interface.name),
+        });
+
+    found.ok_or_else(|| InterfaceNotFound {
+        selector: selector.clone(),
+        candidates: multicast_interfaces()
+            .into_iter()
+            .map(|interface| interface.name)
+            .collect(),
+    })
+}
+
+/// Names of the interfaces with an IPv4 or IPv6 default route, IPv4 first.
+pub fn default_route_interfaces() -> Vec<String> {
+    let mut names = Vec::new();
+
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    if let Ok(routes) = fs::read_to_string("/proc/net/route") {
+        for route in routes.lines().skip(1) {
+            let fields: Vec<_> = route.split_whitespace().collect();
+            if let [name, "00000000", _, flags, _, _, _, "00000000", ..] = fields[..] {
+                if is_usable_route(flags) && !names.iter().any(|known| known == name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+    }
+
+    // Destination PrefixLen Source PrefixLen NextHop Metric RefCnt Use Flags Iface
+    if let Ok(routes) = fs::read_to_string("/proc/net/ipv6_route") {
+        for route in routes.lines() {
+            let fields: Vec<_> = route.split_whitespace().collect();
+            if let [destination, "00", _, _, _, _, _, _, flags, name] = fields[..] {
+                let default = destination.bytes().all(|b| b == b'0');
+                if default && is_usable_route(flags) && !names.iter().any(|known| known == name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+    }
+
+    names
+}
+
+fn is_usable_route(flags: &str) -> bool {
+    u32::from_str_radix(flags, 16).is_ok_and(|flags| flags & RTF_UP != 0 && flags & RTF_REJECT == 0)
+}
+
+/// MTU of an interface as reported in sysfs.
+pub fn mtu(interface: &NetworkInterface) -> Option<u32> {
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", interface.name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Human readable names of the interface flags.
+pub fn flag_names(interface: &NetworkInterface) -> Vec<&'static str> {
+    [
+        (interface.is_up(), "UP"),
+        (interface.is_running(), "RUNNING"),
+        (interface.is_lower_up(), "LOWER_UP"),
+        (interface.is_loopback(), "LOOPBACK"),
+        (interface.is_broadcast(), "BROADCAST"),
+        (interface.is_point_to_point(), "POINTOPOINT"),
+        (interface.is_multicast(), "MULTICAST"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect()
+}
+
+/// Print all multicast capable interfaces with their index, MTU, flags and
+/// addresses.
+pub fn print_interfaces() {
+    let default_routes = default_route_interfaces();
+
+    for interface in multicast_interfaces() {
+        let mtu = mtu(&interface).map_or("?".to_owned(), |mtu| mtu.to_string());
+        let default = match default_routes.contains(&interface.name) {
+            true => " (default route)",
+            false => "",
+        };
+        println!(
+            "{}: {}{default} mtu {mtu} <{}>",
+            interface.index,
+            interface.name,
+            flag_names(&interface).join(",")
+        );
+        for ip in &interface.ips {
+            println!("    {ip}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_selector() {
+        assert_eq!("default".parse(), Ok(InterfaceSelector::DefaultRoute));
+        assert_eq!("2".parse(), Ok(InterfaceSelector::Index(2)));
+        assert_eq!(
+            "fd00::2".parse(),
+            Ok(InterfaceSelector::Address("fd00::2".parse().unwrap()))
+        );
+        assert_eq!(
+            "192.0.2.2".parse(),
+            Ok(InterfaceSelector::Address("192.0.2.2".parse().unwrap()))
+        );
+        assert_eq!(
+            "eth0".parse(),
+            Ok(InterfaceSelector::Name("eth0".to_owned()))
+        );
+    }
+
+    #[test]
+    fn not_found_lists_candidates() {
+        let error =
+            select_interface(&InterfaceSelector::Name("does-not-exist0".to_owned())).unwrap_err();
+        let candidates: Vec<_> = multicast_interfaces()
+            .into_iter()
+            .map(|interface| interface.name)
+            .collect();
+        assert_eq!(error.candidates, candidates);
+        for candidate in candidates {
+            assert!(error.to_string().contains(&candidate));
+        }
+    }
+
+    #[test]
+    fn select_by_index_and_address() {
+        for interface in multicast_interfaces() {
+            let by_index = select_interface(&InterfaceSelector::Index(interface.index)).unwrap();
+            assert_eq!(by_index.name, interface.name);
+            for ip in &interface.ips {
+                let by_address = select_interface(&InterfaceSelector::Address(ip.ip())).unwrap();
+                assert_eq!(by_address.index, interface.index);
+            }
+        }
+    }
+
+    #[test]
+    fn selects_loopback_explicitly() {
+        let by_name = select_interface(&InterfaceSelector::Name("lo".to_owned())).unwrap();
+        assert!(by_name.is_loopback());
+        let by_address =
+            select_interface(&InterfaceSelector::Address("127.0.0.1".parse().unwrap())).unwrap();
+        assert_eq!(by_address.index, by_name.index);
+        if let Ok(default) = select_interface(&InterfaceSelector::DefaultRoute) {
+            assert!(!default.is_loopback());
+        }
+    }
+}
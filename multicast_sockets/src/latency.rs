@@ -0,0 +1,267 @@
+//! One-way latency and interarrival jitter of timestamped packets.
+//!
+//! The one-way latency is only meaningful if the clocks of sender and
+//! receiver are synchronized. The jitter is computed as described in
+//! [RFC 3550, section 6.4.1](https://www.rfc-editor.org/rfc/rfc3550#section-6.4.1)
+//! and is independent of a constant clock offset.
+
+use std::{fmt, time::Duration};
+
+use nix::sys::time::{TimeVal, TimeValLike};
+
+/// Number of power-of-two microsecond buckets, the last one ends at ~16.8s.
+const BUCKETS: usize = 25;
+/// Transit times beyond a day in either direction come from malformed
+/// timestamps rather than clock offsets and are not recorded.
+const MAX_TRANSIT_NS: i128 = 24 * 3600 * 1_000_000_000;
+
+/// Convert a kernel timestamp to nanoseconds since the UNIX epoch.
+pub fn timeval_to_nanos(tv: &TimeVal) -> i64 {
+    tv.num_nanoseconds()
+}
+
+/// Current wall-clock time as nanoseconds since the UNIX epoch.
+pub fn now_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Tracks latency and jitter of a single stream.
+#[derive(Debug, Default, Clone)]
+pub struct LatencyTracker {
+    count: u64,
+    sum_ns: i128,
+    min_ns: i64,
+    max_ns: i64,
+    /// Transit time of the previous packet.
+    last_transit_ns: Option<i64>,
+    /// Interarrival jitter estimate in nanoseconds.
+    jitter_ns: f64,
+    histogram: Histogram,
+}
+
+/// Histogram of latencies in power-of-two microsecond buckets.
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    /// Latencies below zero, caused by unsynchronized clocks.
+    negative: u64,
+    buckets: [u64; BUCKETS],
+    overflow: u64,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a packet sent at `sent_ns` and received at `received_ns`, both
+    /// in nanoseconds since the UNIX epoch. Returns the one-way latency, or
+    /// `None` if it is implausible and was not recorded.
+    pub fn record(&mut self, sent_ns: u64, received_ns: i64) -> Option<i64> {
+        let transit = i128::from(received_ns) - i128::from(sent_ns);
+        if transit.abs() > MAX_TRANSIT_NS {
+            return None;
+        }
+        let transit = transit as i64;
+
+        if let Some(last_transit) = self.last_transit_ns {
+            let deviation = (transit - last_transit).abs() as f64;
+            self.jitter_ns += (deviation - self.jitter_ns) / 16.0;
+        }
+        self.last_transit_ns = Some(transit);
+
+        if self.count == 0 {
+            self.min_ns = transit;
+            self.max_ns = transit;
+        } else {
+            self.min_ns = self.min_ns.min(transit);
+            self.max_ns = self.max_ns.max(transit);
+        }
+        self.count += 1;
+        self.sum_ns += i128::from(transit);
+        self.histogram.record(transit);
+
+        Some(transit)
+    }
+
+    /// Number of recorded packets.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Mean one-way latency in nanoseconds.
+    pub fn mean_ns(&self) -> i64 {
+        if self.count == 0 {
+            return 0;
+        }
+        (self.sum_ns / i128::from(self.count)) as i64
+    }
+
+    pub fn min_ns(&self) -> i64 {
+        self.min_ns
+    }
+
+    pub fn max_ns(&self) -> i64 {
+        self.max_ns
+    }
+
+    /// Current interarrival jitter estimate.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_nanos(self.jitter_ns as u64)
+    }
+
+    pub fn histogram(&self) -> &Histogram {
+        &self.histogram
+    }
+}
+
+impl fmt::Display for LatencyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "no timestamped packets");
+        }
+        write!(
+            f,
+            "latency min {} / mean {} / max {}, jitter {:?}",
+            format_nanos(self.min_ns),
+            format_nanos(self.mean_ns()),
+            format_nanos(self.max_ns),
+            self.jitter(),
+        )
+    }
+}
+
+impl Histogram {
+    /// Estimate of the latency below which the fraction `quantile` of the
+    /// packets was received, as the upper bound of the bucket it falls into.
+    /// Returns `None` without recorded packets.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let total = self.negative + self.overflow + self.buckets.iter().copied().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+
+        let mut seen = self.negative;
+        if seen >= rank {
+            return Some(Duration::ZERO);
+        }
+        for (idx, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << idx));
+            }
+        }
+        // Beyond the last bucket, only the lower bound is known.
+        Some(Duration::from_micros(1 << (BUCKETS - 1)))
+    }
+
+    fn record(&mut self, latency_ns: i64) {
+        if latency_ns < 0 {
+            self.negative += 1;
+            return;
+        }
+
+        let micros = (latency_ns / 1000) as u64;
+        // Bucket `i` holds latencies in `[2^(i-1), 2^i)` microseconds.
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        match self.buckets.get_mut(bucket) {
+            Some(count) => *count += 1,
+            None => self.overflow += 1,
+        }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.negative + self.overflow + self.buckets.iter().copied().sum::<u64>();
+        let bar = |count: u64| "#".repeat((count * 40).div_ceil(total.max(1)) as usize);
+
+        if self.negative > 0 {
+            writeln!(
+                f,
+                "  {:>18} {:>10} {}",
+                "< 0",
+                self.negative,
+                bar(self.negative)
+            )?;
+        }
+        for (idx, &count) in self.buckets.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let upper = Duration::from_micros(1 << idx);
+            writeln!(
+                f,
+                "  {:>18} {:>10} {}",
+                format!("< {upper:?}"),
+                count,
+                bar(count)
+            )?;
+        }
+        if self.overflow > 0 {
+            let lower = Duration::from_micros(1 << (BUCKETS - 1));
+            writeln!(
+                f,
+                "  {:>18} {:>10} {}",
+                format!(">= {lower:?}"),
+                self.overflow,
+                bar(self.overflow)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_nanos(nanos: i64) -> String {
+    let formatted = format!("{:?}", Duration::from_nanos(nanos.unsigned_abs()));
+    match nanos < 0 {
+        true => format!("-{formatted}"),
+        false => formatted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_buckets() {
+        let mut tracker = LatencyTracker::new();
+        assert_eq!(tracker.histogram().percentile(0.5), None);
+
+        // 90 packets below 16µs, 10 below 1.024ms.
+        for _ in 0..90 {
+            tracker.record(0, 10_000);
+        }
+        for _ in 0..10 {
+            tracker.record(0, 1_000_000);
+        }
+
+        let histogram = tracker.histogram();
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(16)));
+        assert_eq!(histogram.percentile(0.9), Some(Duration::from_micros(16)));
+        assert_eq!(
+            histogram.percentile(0.99),
+            Some(Duration::from_micros(1024))
+        );
+    }
+
+    #[test]
+    fn ignores_implausible_timestamps() {
+        let mut tracker = LatencyTracker::new();
+        assert_eq!(tracker.record(u64::MAX, 1_000), None);
+        assert_eq!(tracker.record(0, i64::MAX), None);
+        assert_eq!(tracker.record(1_000, i64::MIN), None);
+        assert_eq!(tracker.count(), 0);
+
+        assert_eq!(tracker.record(1_000, 3_000), Some(2_000));
+        assert_eq!(tracker.record(u64::MAX, 5_000), None);
+        assert_eq!(tracker.record(4_000, 5_000), Some(1_000));
+        assert_eq!(tracker.count(), 2);
+        assert_eq!(tracker.max_ns(), 2_000);
+        assert_eq!(tracker.jitter(), Duration::from_nanos(62));
+    }
+}
@@ -1,8 +1,138 @@
+use std::{
+    mem::{size_of, MaybeUninit},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        signal::{signal, SigHandler, Signal},
+        socket::{AddressFamily, SockaddrLike, SockaddrStorage},
+    },
+};
 use pnet_datalink::NetworkInterface;
 
+#[cfg(feature = "tokio")]
+pub mod async_receiver;
+pub mod control;
+pub mod crypto;
+pub mod fanout;
+pub mod fec;
+pub mod fragment;
+pub mod groups;
+pub mod interface;
+pub mod latency;
+pub mod membership;
+pub mod nack;
+pub mod output;
+pub mod pacing;
+pub mod packet;
+pub mod pcap;
+pub mod receive;
+pub mod scope;
+pub mod send;
+pub mod stats;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+
+/// Cleared by the `SIGINT` handler to stop and print final statistics.
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
 pub fn get_interface_by_name(name: &str) -> Option<NetworkInterface> {
     pnet_datalink::interfaces()
         .into_iter()
         .filter(pnet_datalink::NetworkInterface::is_multicast)
         .find(|intf| intf.name == name)
 }
+
+/// Socket address family matching an IP address.
+pub fn address_family(addr: &IpAddr) -> AddressFamily {
+    match addr {
+        IpAddr::V4(_) => AddressFamily::Inet,
+        IpAddr::V6(_) => AddressFamily::Inet6,
+    }
+}
+
+/// Convert a socket address returned by the kernel to a std socket address.
+pub fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    match addr.family()? {
+        AddressFamily::Inet => addr
+            .as_sockaddr_in()
+            .map(|addr| SocketAddr::V4(SocketAddrV4::from(*addr))),
+        AddressFamily::Inet6 => addr
+            .as_sockaddr_in6()
+            .map(|addr| SocketAddr::V6(SocketAddrV6::from(*addr))),
+        _ => None,
+    }
+}
+
+/// Install a `SIGINT` handler after which [`running`] returns `false`.
+pub fn stop_on_sigint() -> nix::Result<()> {
+    // SAFETY:
+    // `handle_sigint` only stores to an atomic which is async-signal-safe.
+    unsafe { signal(Signal::SIGINT, SigHandler::Handler(handle_sigint)) }.map(drop)
+}
+
+/// Whether no `SIGINT` was received since [`stop_on_sigint`].
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Make [`running`] return `false`, as after a `SIGINT`.
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+extern "C" fn handle_sigint(_: nix::libc::c_int) {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Set a socket option which nix does not wrap (for all target platforms).
+pub(crate) fn set_option<T>(
+    socket: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> nix::Result<()> {
+    // SAFETY:
+    // The pointer and length describe a valid `T` which outlives the call.
+    let res = unsafe {
+        libc::setsockopt(
+            socket,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Read an integer socket option which nix does not wrap (for all target
+/// platforms).
+pub(crate) fn get_int_option(
+    socket: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> nix::Result<libc::c_int> {
+    let mut value = MaybeUninit::<libc::c_int>::zeroed();
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY:
+    // The pointer and length describe writable memory for a `c_int`.
+    let res = unsafe {
+        libc::getsockopt(
+            socket,
+            level,
+            name,
+            value.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    Errno::result(res)?;
+    // SAFETY:
+    // The value was zero-initialized and any bit pattern is a valid `c_int`.
+    Ok(unsafe { value.assume_init() })
+}
@@ -0,0 +1,283 @@
+//! Multicast group membership for IPv4 and IPv6 sockets.
+//!
+//! nix only wraps `ip_mreq`, which selects the interface by address, and
+//! offers no way to create an `Ipv6MembershipRequest` for a specific
+//! interface (https://github.com/nix-rust/nix/issues/323). It does not wrap
+//! the protocol independent `MCAST_*` options for source-specific multicast
+//! at all. Therefore the libc request structs are built here and passed to
+//! `setsockopt` directly.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    os::fd::RawFd,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{self, group_source_req, in6_addr, in_addr, ip_mreqn, ipv6_mreq, sockaddr_storage},
+    sys::socket::{SockaddrLike, SockaddrStorage},
+};
+
+use crate::set_option;
+
+/// Membership of a socket in a multicast group on one interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Membership {
+    pub group: IpAddr,
+    /// Only receive from this source (SSM) instead of from any source (ASM).
+    pub source: Option<IpAddr>,
+    pub interface_index: u32,
+}
+
+impl Membership {
+    /// Any-source membership in `group`.
+    pub fn any_source(group: IpAddr, interface_index: u32) -> Self {
+        Self {
+            group,
+            source: None,
+            interface_index,
+        }
+    }
+
+    /// Source-specific membership in `group` for `source`.
+    pub fn source_specific(group: IpAddr, source: IpAddr, interface_index: u32) -> Self {
+        Self {
+            group,
+            source: Some(source),
+            interface_index,
+        }
+    }
+}
+
+impl fmt::Display for Membership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.group)?;
+        if let Some(source) = self.source {
+            write!(f, " from {source}")?;
+        }
+        write!(f, " on interface {}", self.interface_index)
+    }
+}
+
+/// Join the group of `membership` on the socket.
+pub fn join(socket: RawFd, membership: &Membership) -> nix::Result<()> {
+    update(socket, membership, true)
+}
+
+/// Leave the group of `membership` on the socket.
+pub fn leave(socket: RawFd, membership: &Membership) -> nix::Result<()> {
+    update(socket, membership, false)
+}
+
+/// Filter out `source` on an any-source group joined before.
+pub fn block_source(
+    socket: RawFd,
+    group: &IpAddr,
+    source: &IpAddr,
+    interface_index: u32,
+) -> nix::Result<()> {
+    let request = get_source_group_request(group, source, interface_index)?;
+    set_option(socket, level(group), libc::MCAST_BLOCK_SOURCE, &request)
+}
+
+/// Remove a block added with [`block_source`].
+pub fn unblock_source(
+    socket: RawFd,
+    group: &IpAddr,
+    source: &IpAddr,
+    interface_index: u32,
+) -> nix::Result<()> {
+    let request = get_source_group_request(group, source, interface_index)?;
+    set_option(socket, level(group), libc::MCAST_UNBLOCK_SOURCE, &request)
+}
+
+/// Whether `group` is in the source-specific multicast range, `232.0.0.0/8`
+/// for IPv4 and `ff3x::/32` for IPv6.
+pub fn is_ssm_group(group: &IpAddr) -> bool {
+    match group {
+        IpAddr::V4(group) => group.octets()[0] == 232,
+        IpAddr::V6(group) => {
+            let segments = group.segments();
+            segments[0] & 0xfff0 == 0xff30 && segments[1] == 0
+        }
+    }
+}
+
+/// Build a request for `source` on `group` on the given interface.
+///
+/// Fails with `EINVAL` if the addresses are of different families.
+pub fn get_source_group_request(
+    group: &IpAddr,
+    source: &IpAddr,
+    interface_index: u32,
+) -> nix::Result<group_source_req> {
+    if group.is_ipv4() != source.is_ipv4() {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(group_source_req {
+        gsr_interface: interface_index,
+        gsr_group: to_sockaddr_storage(group),
+        gsr_source: to_sockaddr_storage(source),
+    })
+}
+
+fn update(socket: RawFd, membership: &Membership, join: bool) -> nix::Result<()> {
+    let Membership {
+        group,
+        source,
+        interface_index,
+    } = membership;
+
+    if let Some(source) = source {
+        let request = get_source_group_request(group, source, *interface_index)?;
+        let name = match join {
+            true => libc::MCAST_JOIN_SOURCE_GROUP,
+            false => libc::MCAST_LEAVE_SOURCE_GROUP,
+        };
+        return set_option(socket, level(group), name, &request);
+    }
+
+    match group {
+        IpAddr::V4(group) => {
+            let request = ip_mreqn {
+                imr_multiaddr: in_addr {
+                    s_addr: u32::from(*group).to_be(),
+                },
+                imr_address: in_addr { s_addr: 0 },
+                imr_ifindex: *interface_index as i32,
+            };
+            let name = match join {
+                true => libc::IP_ADD_MEMBERSHIP,
+                false => libc::IP_DROP_MEMBERSHIP,
+            };
+            set_option(socket, libc::IPPROTO_IP, name, &request)
+        }
+        IpAddr::V6(group) => {
+            let request = ipv6_mreq {
+                ipv6mr_multiaddr: in6_addr {
+                    s6_addr: group.octets(),
+                },
+                // Depending on the target platform, this is a `u32` or `i32`.
+                ipv6mr_interface: *interface_index as _,
+            };
+            let name = match join {
+                true => libc::IPV6_ADD_MEMBERSHIP,
+                false => libc::IPV6_DROP_MEMBERSHIP,
+            };
+            set_option(socket, libc::IPPROTO_IPV6, name, &request)
+        }
+    }
+}
+
+fn level(group: &IpAddr) -> libc::c_int {
+    match group {
+        IpAddr::V4(_) => libc::IPPROTO_IP,
+        IpAddr::V6(_) => libc::IPPROTO_IPV6,
+    }
+}
+
+fn to_sockaddr_storage(addr: &IpAddr) -> sockaddr_storage {
+    let addr = SockaddrStorage::from(SocketAddr::new(*addr, 0));
+
+    // SAFETY:
+    // `SockaddrStorage` is a union of all socket address types including
+    // `sockaddr_storage` and therefore at least as large as it.
+    unsafe { *(addr.as_ptr() as *const sockaddr_storage) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use nix::{
+        net::if_::if_nametoindex,
+        sys::socket::{socket, SockFlag, SockType},
+        unistd::close,
+    };
+
+    use super::*;
+
+    const GROUP_V4: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 10, 1));
+    const GROUP_V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0xff14, 0, 0, 0, 0, 0, 0, 0x1a));
+    const SSM_GROUP_V4: IpAddr = IpAddr::V4(Ipv4Addr::new(232, 10, 0, 1));
+    const SSM_GROUP_V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0xff34, 0, 0, 0, 0, 0, 0, 0x1a));
+    const SOURCE_V4: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const SOURCE_V6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+    struct Socket(RawFd);
+
+    impl Socket {
+        fn new(group: &IpAddr) -> Self {
+            let family = crate::address_family(group);
+            Self(socket(family, SockType::Datagram, SockFlag::empty(), None).unwrap())
+        }
+    }
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            let _ = close(self.0);
+        }
+    }
+
+    fn loopback() -> u32 {
+        if_nametoindex("lo").unwrap()
+    }
+
+    #[test]
+    fn join_and_leave_any_source() {
+        for group in [GROUP_V4, GROUP_V6] {
+            let socket = Socket::new(&group);
+            let membership = Membership::any_source(group, loopback());
+
+            join(socket.0, &membership).unwrap();
+            assert_eq!(join(socket.0, &membership), Err(Errno::EADDRINUSE));
+            leave(socket.0, &membership).unwrap();
+            assert_eq!(leave(socket.0, &membership), Err(Errno::EADDRNOTAVAIL));
+        }
+    }
+
+    #[test]
+    fn join_and_leave_source_specific() {
+        for (group, source) in [(SSM_GROUP_V4, SOURCE_V4), (SSM_GROUP_V6, SOURCE_V6)] {
+            let socket = Socket::new(&group);
+            let membership = Membership::source_specific(group, source, loopback());
+
+            join(socket.0, &membership).unwrap();
+            leave(socket.0, &membership).unwrap();
+            assert!(leave(socket.0, &membership).is_err());
+        }
+    }
+
+    #[test]
+    fn block_and_unblock_source() {
+        for (group, source) in [(GROUP_V4, SOURCE_V4), (GROUP_V6, SOURCE_V6)] {
+            let socket = Socket::new(&group);
+            join(socket.0, &Membership::any_source(group, loopback())).unwrap();
+
+            block_source(socket.0, &group, &source, loopback()).unwrap();
+            unblock_source(socket.0, &group, &source, loopback()).unwrap();
+            assert_eq!(
+                unblock_source(socket.0, &group, &source, loopback()),
+                Err(Errno::EADDRNOTAVAIL)
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_rejected() {
+        let socket = Socket::new(&SSM_GROUP_V6);
+        let membership = Membership::source_specific(SSM_GROUP_V6, SOURCE_V4, loopback());
+        assert_eq!(join(socket.0, &membership), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn ssm_ranges() {
+        assert!(is_ssm_group(&SSM_GROUP_V4));
+        assert!(is_ssm_group(&SSM_GROUP_V6));
+        assert!(!is_ssm_group(&GROUP_V4));
+        assert!(!is_ssm_group(&GROUP_V6));
+        assert!(!is_ssm_group(&"ff35:1::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,553 @@
+//! NACK based reliability on top of the multicast stream.
+//!
+//! Receivers detect gaps in the sequence numbers with a [`NackTracker`] and
+//! send [`Nack`]s unicast to the source address of the stream, which is the
+//! socket of the sender. The sender keeps its recently sent packets in a
+//! [`RetransmitBuffer`] and sends the requested ones again to the group,
+//! marked with [`Flags::RETRANSMIT`], so every receiver missing them is
+//! repaired at once. Losses at the very end of a stream are not detected,
+//! as no later packet reveals the gap.
+//!
+//! NACKs are not authenticated, so the sender queues at most one
+//! retransmission per buffered packet and sends them within its packet rate.
+//!
+//! A NACK datagram is, in network byte order:
+//!
+//! ```text
+//! +---------------------------------------------------------------+
+//! |                         magic "MCNK"                          |
+//! +---------------+---------------+-------------------------------+
+//! |    version    |   reserved    |           stream id           |
+//! +---------------+---------------+-------------------------------+
+//! |       number of ranges        |
+//! +-------------------------------+-------------------------------+
+//! |                    first missing sequence                     |
+//! +-------------------------------+-------------------------------+
+//! |        number of packets      |  further ranges ...
+//! +-------------------------------+
+//! ```
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    os::fd::RawFd,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+use nix::{
+    errno::Errno,
+    sys::socket::{recvfrom, sendto, MsgFlags, SockaddrStorage},
+};
+
+use crate::{
+    packet::{self, Flags, Packet},
+    to_socket_addr,
+};
+
+/// Magic number at the start of every NACK.
+pub const NACK_MAGIC: u32 = u32::from_be_bytes(*b"MCNK");
+/// Current version of the NACK format.
+pub const NACK_VERSION: u8 = 1;
+const NACK_HEADER_LEN: usize = 10;
+const RANGE_LEN: usize = 6;
+/// Most ranges in one NACK, which keeps it below 1400 bytes.
+pub const MAX_RANGES: usize = 200;
+/// Most missing packets remembered per gap, like the reorder window of
+/// [`crate::stats::SequenceTracker`].
+pub const MAX_MISSING: u32 = 1024;
+
+/// Request to retransmit ranges of packets of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nack {
+    pub stream_id: u16,
+    /// First missing sequence number and number of consecutive missing ones.
+    pub ranges: Vec<(u32, u16)>,
+}
+
+/// Reasons why a datagram is not a valid NACK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackError {
+    TooShort(usize),
+    BadMagic(u32),
+    UnsupportedVersion(u8),
+    LengthMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for NackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NackError::TooShort(len) => write!(f, "NACK of {len} bytes too short"),
+            NackError::BadMagic(magic) => write!(f, "bad NACK magic {magic:#010x}"),
+            NackError::UnsupportedVersion(version) => {
+                write!(f, "unsupported NACK version {version}")
+            }
+            NackError::LengthMismatch { expected, actual } => {
+                write!(f, "expected NACK of {expected} bytes but got {actual}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NackError {}
+
+impl Nack {
+    /// NACK for the given missing sequence numbers, which are merged into
+    /// ranges. Only the first [`MAX_RANGES`] ranges are kept.
+    pub fn from_sequences(stream_id: u16, sequences: impl IntoIterator<Item = u32>) -> Self {
+        let mut ranges: Vec<(u32, u16)> = Vec::new();
+        for sequence in sequences {
+            let full = ranges.len() == MAX_RANGES;
+            match ranges.last_mut() {
+                Some((first, count))
+                    if first.wrapping_add(u32::from(*count)) == sequence && *count < u16::MAX =>
+                {
+                    *count += 1
+                }
+                _ if full => break,
+                _ => ranges.push((sequence, 1)),
+            }
+        }
+        Self { stream_id, ranges }
+    }
+
+    /// All requested sequence numbers.
+    pub fn sequences(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ranges.iter().flat_map(|&(first, count)| {
+            (0..u32::from(count)).map(move |offset| first.wrapping_add(offset))
+        })
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.clear();
+        out.reserve(NACK_HEADER_LEN + self.ranges.len() * RANGE_LEN);
+        out.put_u32(NACK_MAGIC);
+        out.put_u8(NACK_VERSION);
+        out.put_u8(0);
+        out.put_u16(self.stream_id);
+        out.put_u16(self.ranges.len() as u16);
+        for &(first, count) in &self.ranges {
+            out.put_u32(first);
+            out.put_u16(count);
+        }
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self, NackError> {
+        if datagram.len() < NACK_HEADER_LEN {
+            return Err(NackError::TooShort(datagram.len()));
+        }
+
+        let mut buf = datagram;
+        let magic = buf.get_u32();
+        if magic != NACK_MAGIC {
+            return Err(NackError::BadMagic(magic));
+        }
+        let version = buf.get_u8();
+        if version != NACK_VERSION {
+            return Err(NackError::UnsupportedVersion(version));
+        }
+        let _reserved = buf.get_u8();
+        let stream_id = buf.get_u16();
+        let range_count = usize::from(buf.get_u16());
+
+        let expected = NACK_HEADER_LEN + range_count * RANGE_LEN;
+        if datagram.len() != expected {
+            return Err(NackError::LengthMismatch {
+                expected,
+                actual: datagram.len(),
+            });
+        }
+
+        let ranges = (0..range_count)
+            .map(|_| (buf.get_u32(), buf.get_u16()))
+            .collect();
+        Ok(Self { stream_id, ranges })
+    }
+}
+
+/// Send a NACK to the source of a stream.
+pub fn send_nack(socket: RawFd, nack: &Nack, destination: SocketAddr) -> nix::Result<()> {
+    let mut datagram = Vec::new();
+    nack.encode(&mut datagram);
+    let destination = SockaddrStorage::from(destination);
+    sendto(socket, &datagram, &destination, MsgFlags::empty()).map(drop)
+}
+
+/// Receive the next datagram on the non-blocking sender socket. Returns
+/// `None` if there is none, or the NACK decode error with the sender's
+/// address otherwise.
+pub fn recv_nack(socket: RawFd) -> nix::Result<Option<(Result<Nack, NackError>, SocketAddr)>> {
+    let mut buf = [0; 1500];
+    match recvfrom::<SockaddrStorage>(socket, &mut buf) {
+        Ok((len, Some(source))) => {
+            let source = to_socket_addr(&source).ok_or(Errno::EAFNOSUPPORT)?;
+            Ok(Some((Nack::decode(&buf[..len]), source)))
+        }
+        Ok((_, None)) => Err(Errno::EAFNOSUPPORT),
+        Err(Errno::EAGAIN) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The most recently sent packets of a stream, indexed by sequence number,
+/// and the requested retransmissions.
+#[derive(Debug, Clone)]
+pub struct RetransmitBuffer {
+    slots: Vec<Option<Slot>>,
+    /// Requested sequence numbers, oldest request first. Packets replaced
+    /// after the request are skipped.
+    queue: VecDeque<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    sequence: u32,
+    datagram: Vec<u8>,
+    queued: bool,
+}
+
+impl RetransmitBuffer {
+    /// Buffer for the last `capacity` packets.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: vec![None; capacity.max(1)],
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Remember an encoded packet, replacing the oldest one.
+    pub fn insert(&mut self, sequence: u32, datagram: &[u8]) {
+        let idx = sequence as usize % self.slots.len();
+        match &mut self.slots[idx] {
+            Some(slot) => {
+                slot.sequence = sequence;
+                slot.datagram.clear();
+                slot.datagram.extend_from_slice(datagram);
+                slot.queued = false;
+            }
+            slot => {
+                *slot = Some(Slot {
+                    sequence,
+                    datagram: datagram.to_vec(),
+                    queued: false,
+                })
+            }
+        }
+    }
+
+    /// The packet with `sequence`, if it is still buffered.
+    pub fn get(&self, sequence: u32) -> Option<&[u8]> {
+        let idx = sequence as usize % self.slots.len();
+        match &self.slots[idx] {
+            Some(slot) if slot.sequence == sequence => Some(&slot.datagram),
+            _ => None,
+        }
+    }
+
+    /// Queue the retransmissions of the packets requested by `nack` and
+    /// return how many of them are not buffered anymore. A packet is queued
+    /// once however often it is requested, and no more packets than the
+    /// buffer holds are queued or looked at per NACK.
+    pub fn request(&mut self, nack: &Nack) -> u64 {
+        let capacity = self.slots.len();
+        let mut unavailable = 0;
+        for sequence in nack.sequences().take(capacity) {
+            match &mut self.slots[sequence as usize % capacity] {
+                Some(slot) if slot.sequence == sequence => {
+                    if !slot.queued && self.queue.len() < capacity {
+                        slot.queued = true;
+                        self.queue.push_back(sequence);
+                    }
+                }
+                _ => unavailable += 1,
+            }
+        }
+        unavailable
+    }
+
+    /// Number of queued retransmissions.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Pass up to `max` queued retransmissions to `retransmit` and return
+    /// how many were passed.
+    pub fn retransmit_queued(&mut self, max: u32, mut retransmit: impl FnMut(Packet)) -> u32 {
+        let mut passed = 0;
+        while passed < max {
+            let Some(sequence) = self.queue.pop_front() else {
+                break;
+            };
+            let idx = sequence as usize % self.slots.len();
+            match &mut self.slots[idx] {
+                Some(slot) if slot.sequence == sequence => slot.queued = false,
+                _ => continue,
+            }
+            if let Some(packet) = self.retransmission(sequence) {
+                retransmit(packet);
+                passed += 1;
+            }
+        }
+        passed
+    }
+
+    /// The retransmission of `sequence`, which is the original packet with
+    /// [`Flags::RETRANSMIT`] set, or `None` if it is not buffered anymore.
+    pub fn retransmission(&self, sequence: u32) -> Option<Packet<'_>> {
+        let mut packet = packet::decode(self.get(sequence)?).ok()?;
+        packet.header.flags.set(Flags::RETRANSMIT, true);
+        Some(packet)
+    }
+}
+
+/// Counters of a [`NackTracker`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NackStats {
+    /// Sent NACK datagrams.
+    pub nacks: u64,
+    /// Sequence numbers requested, including repeated requests.
+    pub requested: u64,
+    /// Missing packets given up on after all attempts.
+    pub abandoned: u64,
+}
+
+impl fmt::Display for NackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NACKs {}, requested {}, abandoned {}",
+            self.nacks, self.requested, self.abandoned
+        )
+    }
+}
+
+/// State of a missing packet.
+#[derive(Debug, Clone, Copy)]
+struct Missing {
+    last_request: Option<Instant>,
+    attempts: u8,
+}
+
+/// Decides which missing packets of a stream to request, and when to
+/// request them again.
+#[derive(Debug, Clone)]
+pub struct NackTracker {
+    interval: Duration,
+    max_attempts: u8,
+    missing: BTreeMap<u32, Missing>,
+    stats: NackStats,
+}
+
+impl NackTracker {
+    /// Request every missing packet up to `max_attempts` times, `interval`
+    /// apart.
+    pub fn new(interval: Duration, max_attempts: u8) -> Self {
+        Self {
+            interval,
+            max_attempts: max_attempts.max(1),
+            missing: BTreeMap::new(),
+            stats: NackStats::default(),
+        }
+    }
+
+    /// Remember the `gap` packets before `sequence` as missing.
+    pub fn on_gap(&mut self, sequence: u32, gap: u32) {
+        let remembered = gap.min(MAX_MISSING);
+        for back in (1..=remembered).rev() {
+            self.missing.insert(
+                sequence.wrapping_sub(back),
+                Missing {
+                    last_request: None,
+                    attempts: 0,
+                },
+            );
+        }
+    }
+
+    /// A missing packet arrived, possibly as a retransmission.
+    pub fn on_received(&mut self, sequence: u32) {
+        self.missing.remove(&sequence);
+    }
+
+    /// The NACK to send now for `stream_id`, if any packets are due.
+    pub fn poll(&mut self, stream_id: u16, now: Instant) -> Option<Nack> {
+        let interval = self.interval;
+        let max_attempts = self.max_attempts;
+        let stats = &mut self.stats;
+        self.missing.retain(|_, missing| {
+            let due = missing
+                .last_request
+                .is_none_or(|last| now.duration_since(last) >= interval);
+            let abandoned = due && missing.attempts >= max_attempts;
+            if abandoned {
+                stats.abandoned += 1;
+            }
+            !abandoned
+        });
+
+        let due: Vec<u32> = self
+            .missing
+            .iter()
+            .filter(|(_, missing)| {
+                missing
+                    .last_request
+                    .is_none_or(|last| now.duration_since(last) >= interval)
+            })
+            .map(|(&sequence, _)| sequence)
+            .collect();
+        if due.is_empty() {
+            return None;
+        }
+
+        let nack = Nack::from_sequences(stream_id, due);
+        for sequence in nack.sequences() {
+            if let Some(missing) = self.missing.get_mut(&sequence) {
+                missing.last_request = Some(now);
+                missing.attempts += 1;
+                self.stats.requested += 1;
+            }
+        }
+        self.stats.nacks += 1;
+        Some(nack)
+    }
+
+    /// Current statistics of this stream.
+    pub fn stats(&self) -> NackStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pacing::TokenBucket, packet::Header};
+
+    #[test]
+    fn encode_and_decode() {
+        let nack = Nack::from_sequences(3, [5, 6, 7, 9, u32::MAX, 0]);
+        assert_eq!(nack.ranges, [(5, 3), (9, 1), (u32::MAX, 2)]);
+        assert_eq!(
+            nack.sequences().collect::<Vec<_>>(),
+            [5, 6, 7, 9, u32::MAX, 0]
+        );
+
+        let mut datagram = Vec::new();
+        nack.encode(&mut datagram);
+        assert_eq!(Nack::decode(&datagram), Ok(nack));
+
+        assert_eq!(
+            Nack::decode(&datagram[..datagram.len() - 1]),
+            Err(NackError::LengthMismatch {
+                expected: datagram.len(),
+                actual: datagram.len() - 1
+            })
+        );
+        datagram[0] = b'X';
+        assert!(matches!(
+            Nack::decode(&datagram),
+            Err(NackError::BadMagic(_))
+        ));
+    }
+
+    fn insert_packet(buffer: &mut RetransmitBuffer, sequence: u32) {
+        let header = Header {
+            sequence,
+            ..Default::default()
+        };
+        let mut datagram = Vec::new();
+        packet::encode(&header, b"data", &mut datagram);
+        buffer.insert(sequence, &datagram);
+    }
+
+    #[test]
+    fn retransmit_buffer_keeps_latest() {
+        let mut buffer = RetransmitBuffer::new(4);
+        for sequence in 0..6 {
+            insert_packet(&mut buffer, sequence);
+        }
+
+        assert!(buffer.get(1).is_none());
+        assert!(buffer.retransmission(0).is_none());
+        let packet = buffer.retransmission(5).unwrap();
+        assert_eq!(packet.header.sequence, 5);
+        assert!(packet.header.flags.contains(Flags::RETRANSMIT));
+        assert_eq!(packet.payload, b"data");
+    }
+
+    #[test]
+    fn large_nacks_are_paced() {
+        let mut buffer = RetransmitBuffer::new(64);
+        for sequence in 0..80 {
+            insert_packet(&mut buffer, sequence);
+        }
+
+        // Every buffered packet is queued once, at most 64 sequence numbers
+        // of the NACK are looked at.
+        let nack = Nack {
+            stream_id: 0,
+            ranges: vec![(0, u16::MAX); MAX_RANGES],
+        };
+        assert_eq!(buffer.request(&nack), 16);
+        assert_eq!(buffer.request(&nack), 16);
+        assert_eq!(buffer.queued(), 48);
+
+        let rate = 1000.0;
+        let mut bucket = TokenBucket::new(rate, 8);
+        let started = Instant::now();
+        let mut sequences = Vec::new();
+        while buffer.queued() > 0 {
+            let tokens = bucket.take(u32::MAX);
+            buffer.retransmit_queued(tokens, |packet| {
+                assert!(packet.header.flags.contains(Flags::RETRANSMIT));
+                sequences.push(packet.header.sequence);
+            });
+            // Within the rate, after the initial token.
+            let allowed = 1.0 + rate * started.elapsed().as_secs_f64();
+            assert!(sequences.len() as f64 <= allowed, "{sequences:?}");
+        }
+        assert_eq!(sequences, (16..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn requests_of_replaced_packets_are_skipped() {
+        let mut buffer = RetransmitBuffer::new(2);
+        insert_packet(&mut buffer, 0);
+        assert_eq!(buffer.request(&Nack::from_sequences(0, [0])), 0);
+        insert_packet(&mut buffer, 2);
+        assert_eq!(buffer.request(&Nack::from_sequences(0, [2])), 0);
+        assert_eq!(buffer.queued(), 2);
+
+        let mut sequences = Vec::new();
+        let passed = buffer.retransmit_queued(2, |packet| sequences.push(packet.header.sequence));
+        assert_eq!(passed, 1);
+        assert_eq!(sequences, [2]);
+        assert_eq!(buffer.queued(), 0);
+    }
+
+    #[test]
+    fn tracker_repeats_and_abandons() {
+        let interval = Duration::from_millis(10);
+        let mut tracker = NackTracker::new(interval, 2);
+        let start = Instant::now();
+
+        tracker.on_gap(10, 3);
+        let nack = tracker.poll(1, start).unwrap();
+        assert_eq!(nack.ranges, [(7, 3)]);
+        assert!(tracker.poll(1, start + interval / 2).is_none());
+
+        tracker.on_received(8);
+        let nack = tracker.poll(1, start + interval).unwrap();
+        assert_eq!(nack.ranges, [(7, 1), (9, 1)]);
+
+        assert!(tracker.poll(1, start + interval * 2).is_none());
+        assert_eq!(
+            tracker.stats(),
+            NackStats {
+                nacks: 2,
+                requested: 5,
+                abandoned: 2
+            }
+        );
+    }
+}
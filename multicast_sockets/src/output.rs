@@ -0,0 +1,325 @@
+//! Machine readable statistics records.
+//!
+//! With [`OutputFormat::Json`] every record is written as one JSON object
+//! per line, with [`OutputFormat::Csv`] as one row after a header row of the
+//! field names. The records then own stdout, so the human readable status
+//! lines printed with [`status!`](crate::status) move to stderr.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set once structured records are written to stdout.
+static STDOUT_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Print a human readable status line to stdout, or to stderr once
+/// structured records are written to stdout.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::output::print_status(format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn print_status(args: fmt::Arguments) {
+    match STDOUT_CLAIMED.load(Ordering::Relaxed) {
+        true => eprintln!("{args}"),
+        false => println!("{args}"),
+    }
+}
+
+/// How statistics are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Comma separated values with a header row.
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!(
+                "unknown output format {s:?}, expected text, json or csv"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+            Self::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// Value of a record field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::U64(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::U64(value.into())
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::U64(value.into())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::I64(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::F64(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Ordered fields of one statistics record.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Record {
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Record {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a field.
+    pub fn field(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.fields.iter().map(|(name, _)| *name)
+    }
+}
+
+/// Writes records as JSON lines or CSV rows.
+#[derive(Debug)]
+pub struct StatsWriter<W> {
+    format: OutputFormat,
+    out: W,
+    /// Field names of the CSV header once it is written.
+    columns: Option<Vec<&'static str>>,
+}
+
+impl StatsWriter<io::Stdout> {
+    /// Write records in a structured `format` to stdout, which moves the
+    /// status lines to stderr. Returns `None` for [`OutputFormat::Text`].
+    pub fn stdout(format: OutputFormat) -> Option<Self> {
+        if format == OutputFormat::Text {
+            return None;
+        }
+        STDOUT_CLAIMED.store(true, Ordering::Relaxed);
+        Some(Self::new(format, io::stdout()))
+    }
+}
+
+impl<W: Write> StatsWriter<W> {
+    pub fn new(format: OutputFormat, out: W) -> Self {
+        Self {
+            format,
+            out,
+            columns: None,
+        }
+    }
+
+    /// Write one record. For CSV, all records need the fields of the first.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => return Ok(()),
+            OutputFormat::Json => {
+                let mut line = String::from("{");
+                for (idx, (name, value)) in record.fields.iter().enumerate() {
+                    if idx > 0 {
+                        line.push(',');
+                    }
+                    write_json_string(&mut line, name);
+                    line.push(':');
+                    write_json_value(&mut line, value);
+                }
+                line.push('}');
+                writeln!(self.out, "{line}")?;
+            }
+            OutputFormat::Csv => {
+                match &self.columns {
+                    Some(columns) if !columns.iter().copied().eq(record.names()) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "CSV records need the same fields as the header",
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        let columns: Vec<_> = record.names().collect();
+                        writeln!(self.out, "{}", columns.join(","))?;
+                        self.columns = Some(columns);
+                    }
+                }
+                let row: Vec<_> = record
+                    .fields
+                    .iter()
+                    .map(|(_, value)| csv_value(value))
+                    .collect();
+                writeln!(self.out, "{}", row.join(","))?;
+            }
+        }
+        // Pipelines read the records while the binaries are running.
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_json_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::U64(value) => out.push_str(&value.to_string()),
+        Value::I64(value) => out.push_str(&value.to_string()),
+        // JSON has no representation of NaN and infinity.
+        Value::F64(value) if !value.is_finite() => out.push_str("null"),
+        Value::F64(value) => out.push_str(&value.to_string()),
+        Value::Str(value) => write_json_string(out, value),
+    }
+}
+
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::U64(value) => value.to_string(),
+        Value::I64(value) => value.to_string(),
+        Value::F64(value) if !value.is_finite() => String::new(),
+        Value::F64(value) => value.to_string(),
+        Value::Str(value) if value.contains([',', '"', '\n', '\r']) => {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        }
+        Value::Str(value) => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(packets: u64) -> Record {
+        Record::new()
+            .field("record", "interval")
+            .field("stream", "[ff14::1a]:30000 #0, \"a\"")
+            .field("packets", packets)
+            .field("latency_ns", -5i64)
+            .field("loss_rate", 0.25)
+            .field("jitter_ns", None::<u64>)
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let mut writer = StatsWriter::new(OutputFormat::Json, Vec::new());
+        writer.write(&record(3)).unwrap();
+        writer
+            .write(&Record::new().field("rate", f64::NAN).field("s", "a\\b\n"))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "{\"record\":\"interval\",\"stream\":\"[ff14::1a]:30000 #0, \\\"a\\\"\",\
+             \"packets\":3,\"latency_ns\":-5,\"loss_rate\":0.25,\"jitter_ns\":null}\n\
+             {\"rate\":null,\"s\":\"a\\\\b\\n\"}\n"
+        );
+    }
+
+    #[test]
+    fn writes_csv_with_header() {
+        let mut writer = StatsWriter::new(OutputFormat::Csv, Vec::new());
+        writer.write(&record(3)).unwrap();
+        writer.write(&record(4)).unwrap();
+        assert!(writer.write(&Record::new().field("other", 1u64)).is_err());
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "record,stream,packets,latency_ns,loss_rate,jitter_ns\n\
+             interval,\"[ff14::1a]:30000 #0, \"\"a\"\"\",3,-5,0.25,\n\
+             interval,\"[ff14::1a]:30000 #0, \"\"a\"\"\",4,-5,0.25,\n"
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse(), Ok(OutputFormat::Json));
+        assert_eq!("csv".parse(), Ok(OutputFormat::Csv));
+        assert_eq!("text".parse(), Ok(OutputFormat::Text));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
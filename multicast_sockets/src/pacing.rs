@@ -0,0 +1,141 @@
+//! Token bucket pacing for sending at a target packet rate.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+/// Token bucket which refills at `rate` tokens per second up to `capacity`.
+///
+/// One token allows sending one packet. The capacity limits how many packets
+/// are sent back-to-back after an idle period.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a bucket which starts with a single token.
+    pub fn new(rate: f64, capacity: u32) -> Self {
+        let capacity = f64::from(capacity.max(1));
+        Self {
+            rate,
+            capacity,
+            tokens: 1.0f64.min(capacity),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take up to `max` whole tokens and return how many were taken.
+    pub fn take(&mut self, max: u32) -> u32 {
+        self.refill(Instant::now());
+
+        let taken = (self.tokens.floor() as u32).min(max);
+        self.tokens -= f64::from(taken);
+        taken
+    }
+
+    /// Time until the next token is available.
+    pub fn time_to_next(&self) -> Duration {
+        let missing = 1.0 - self.tokens;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        // A tiny rate may wait longer than a `Duration` can hold.
+        Duration::try_from_secs_f64(missing / self.rate).unwrap_or(Duration::MAX)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Parse a packet rate in packets per second, which has to be positive.
+pub fn parse_rate(value: &str) -> Result<f64> {
+    let rate = value
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("invalid packet rate {value:?}"))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        bail!("packet rate must be positive");
+    }
+    Ok(rate)
+}
+
+/// Parse a bit rate with an optional SI suffix, e.g. `"100k"` or `"1.5M"`.
+pub fn parse_bitrate(value: &str) -> Result<f64> {
+    let value = value
+        .trim()
+        .trim_end_matches("bps")
+        .trim_end_matches("bit/s");
+    let (number, multiplier) = match value.chars().last() {
+        Some('k' | 'K') => (&value[..value.len() - 1], 1e3),
+        Some('M') => (&value[..value.len() - 1], 1e6),
+        Some('G') => (&value[..value.len() - 1], 1e9),
+        _ => (value, 1.0),
+    };
+
+    let bitrate = number
+        .parse::<f64>()
+        .with_context(|| format!("invalid bit rate {value:?}"))?
+        * multiplier;
+    if !bitrate.is_finite() || bitrate <= 0.0 {
+        bail!("bit rate must be positive");
+    }
+    Ok(bitrate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_with_one_token_and_refills_up_to_capacity() {
+        let mut bucket = TokenBucket::new(10.0, 4);
+        assert_eq!(bucket.take(8), 1);
+        assert_eq!(bucket.take(8), 0);
+
+        bucket.refill(bucket.last_refill + Duration::from_secs(1));
+        assert_eq!(bucket.take(3), 3);
+        assert_eq!(bucket.take(3), 1);
+    }
+
+    #[test]
+    fn time_to_next_token() {
+        let mut bucket = TokenBucket::new(10.0, 4);
+        assert_eq!(bucket.time_to_next(), Duration::ZERO);
+
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.time_to_next(), Duration::from_millis(50));
+
+        // Waits beyond the range of a `Duration` saturate.
+        let mut bucket = TokenBucket::new(1e-300, 1);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.time_to_next(), Duration::MAX);
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("1000").unwrap(), 1000.0);
+        assert_eq!(parse_rate("0.5").unwrap(), 0.5);
+        for invalid in ["0", "-5", "inf", "NaN", "fast", ""] {
+            assert!(parse_rate(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_bitrates() {
+        assert_eq!(parse_bitrate("500").unwrap(), 500.0);
+        assert_eq!(parse_bitrate("100k").unwrap(), 100e3);
+        assert_eq!(parse_bitrate("1.5M").unwrap(), 1.5e6);
+        assert_eq!(parse_bitrate("10Mbps").unwrap(), 10e6);
+        assert_eq!(parse_bitrate("2Gbit/s").unwrap(), 2e9);
+        for invalid in ["0", "-5k", "inf", "NaN", "10T", ""] {
+            assert!(parse_bitrate(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Versioned packet format shared by sender and receiver.
+//!
+//! Every datagram starts with a fixed header in network byte order:
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                         magic "MCSK"                          |
+//! +---------------+---------------+-------------------------------+
+//! |    version    |     flags     |           stream id           |
+//! +---------------+---------------+-------------------------------+
+//! |                        sequence number                        |
+//! +---------------------------------------------------------------+
+//! |                 send timestamp (ns since epoch)               |
+//! |                                                               |
+//! +-------------------------------+-------------------------------+
+//! |         payload length        |  payload ...
+//! +-------------------------------+
+//! ```
+//!
+//! If [`Flags::CRC`] is set, a CRC-32 over header and payload follows the
+//! payload. If [`Flags::PARITY`] is set, the packet carries FEC parity
+//! instead of application payload. [`Flags::RETRANSMIT`] marks a packet
+//! which is sent again after a NACK and [`Flags::FRAGMENT`] one which carries
+//! a part of a larger message. The payload of a packet with
+//! [`Flags::ENCRYPTED`] is sealed between a nonce and a tag, which are not
+//! counted in the payload length.
+
+use std::fmt;
+
+use bytes::{Buf, BufMut};
+
+use crate::crypto;
+
+/// Magic number at the start of every packet.
+pub const MAGIC: u32 = u32::from_be_bytes(*b"MCSK");
+/// Current version of the packet format.
+pub const VERSION: u8 = 1;
+/// Length of the encoded header in bytes.
+pub const HEADER_LEN: usize = 22;
+/// Length of the optional CRC trailer in bytes.
+pub const CRC_LEN: usize = 4;
+/// Length of the nonce and tag around an encrypted payload in bytes.
+pub const SEAL_LEN: usize = crypto::NONCE_LEN + crypto::TAG_LEN;
+
+/// Flags of a packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    /// A CRC-32 trailer follows the payload.
+    pub const CRC: Flags = Flags(1 << 0);
+    /// The payload is FEC parity over other packets, see [`crate::fec`].
+    pub const PARITY: Flags = Flags(1 << 1);
+    /// The packet is sent again on request, see [`crate::nack`].
+    pub const RETRANSMIT: Flags = Flags(1 << 2);
+    /// The payload is a fragment of a larger message, see [`crate::fragment`].
+    pub const FRAGMENT: Flags = Flags(1 << 3);
+    /// The payload is encrypted and authenticated, see [`crate::crypto`].
+    pub const ENCRYPTED: Flags = Flags(1 << 4);
+
+    pub const fn empty() -> Self {
+        Flags(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: Flags, enabled: bool) {
+        match enabled {
+            true => self.0 |= other.0,
+            false => self.0 &= !other.0,
+        }
+    }
+}
+
+/// Header of a packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub flags: Flags,
+    /// Identifies one stream among several sent by the same sender.
+    pub stream_id: u16,
+    /// Sequence number, incremented per packet of a stream.
+    pub sequence: u32,
+    /// Send time in nanoseconds since the UNIX epoch, zero if unknown.
+    pub timestamp_ns: u64,
+}
+
+/// A decoded packet borrowing its payload from the datagram. The payload of
+/// an encrypted packet is the sealed one, with nonce and tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+/// Reasons why a datagram is not a valid packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The datagram is shorter than the header.
+    TooShort(usize),
+    /// The datagram does not start with [`MAGIC`].
+    BadMagic(u32),
+    /// The packet was encoded with an unknown version.
+    UnsupportedVersion(u8),
+    /// The datagram length does not match the length given in the header.
+    LengthMismatch { expected: usize, actual: usize },
+    /// The CRC trailer does not match the content.
+    CrcMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort(len) => write!(f, "datagram of {len} bytes too short"),
+            DecodeError::BadMagic(magic) => write!(f, "bad magic {magic:#010x}"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {version}")
+            }
+            DecodeError::LengthMismatch { expected, actual } => {
+                write!(f, "expected {expected} bytes but got {actual}")
+            }
+            DecodeError::CrcMismatch { expected, actual } => {
+                write!(f, "CRC {actual:#010x} does not match {expected:#010x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Header {
+    /// Length of a datagram carrying this header and a payload of
+    /// `payload_len` bytes.
+    pub fn encoded_len(&self, payload_len: usize) -> usize {
+        let crc_len = match self.flags.contains(Flags::CRC) {
+            true => CRC_LEN,
+            false => 0,
+        };
+        HEADER_LEN + self.seal_len() + payload_len + crc_len
+    }
+
+    fn seal_len(&self) -> usize {
+        match self.flags.contains(Flags::ENCRYPTED) {
+            true => SEAL_LEN,
+            false => 0,
+        }
+    }
+}
+
+/// Encode a packet into `out`, replacing its previous content. The payload
+/// of an encrypted packet has to be sealed, see [`crypto::Sealer`].
+///
+/// # Panics
+///
+/// Panics if the payload is longer than `u16::MAX` bytes, or if the payload
+/// of an encrypted packet is shorter than [`SEAL_LEN`].
+pub fn encode(header: &Header, payload: &[u8], out: &mut Vec<u8>) {
+    let payload_len = payload
+        .len()
+        .checked_sub(header.seal_len())
+        .expect("sealed payload too short");
+    let payload_len = u16::try_from(payload_len).expect("payload too long");
+
+    out.clear();
+    out.reserve(header.encoded_len(usize::from(payload_len)));
+    out.put_u32(MAGIC);
+    out.put_u8(VERSION);
+    out.put_u8(header.flags.bits());
+    out.put_u16(header.stream_id);
+    out.put_u32(header.sequence);
+    out.put_u64(header.timestamp_ns);
+    out.put_u16(payload_len);
+    out.put_slice(payload);
+
+    if header.flags.contains(Flags::CRC) {
+        let crc = crc32fast::hash(out);
+        out.put_u32(crc);
+    }
+}
+
+/// Decode a packet from a received datagram.
+pub fn decode(datagram: &[u8]) -> Result<Packet<'_>, DecodeError> {
+    if datagram.len() < HEADER_LEN {
+        return Err(DecodeError::TooShort(datagram.len()));
+    }
+
+    let mut buf = datagram;
+    let magic = buf.get_u32();
+    if magic != MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+    let version = buf.get_u8();
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let header = Header {
+        flags: Flags(buf.get_u8()),
+        stream_id: buf.get_u16(),
+        sequence: buf.get_u32(),
+        timestamp_ns: buf.get_u64(),
+    };
+    let payload_len = usize::from(buf.get_u16());
+
+    let expected = header.encoded_len(payload_len);
+    if datagram.len() != expected {
+        return Err(DecodeError::LengthMismatch {
+            expected,
+            actual: datagram.len(),
+        });
+    }
+
+    let payload_end = HEADER_LEN + header.seal_len() + payload_len;
+    if header.flags.contains(Flags::CRC) {
+        let (content, mut trailer) = datagram.split_at(payload_end);
+        let expected = trailer.get_u32();
+        let actual = crc32fast::hash(content);
+        if expected != actual {
+            return Err(DecodeError::CrcMismatch { expected, actual });
+        }
+    }
+
+    Ok(Packet {
+        header,
+        payload: &datagram[HEADER_LEN..payload_end],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(flags: Flags) -> Header {
+        Header {
+            flags,
+            stream_id: 7,
+            sequence: 0xdead_beef,
+            timestamp_ns: 1_690_000_000_123_456_789,
+        }
+    }
+
+    fn encoded(flags: Flags, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(&header(flags), payload, &mut out);
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let datagram = encoded(Flags::empty(), b"hello");
+        assert_eq!(datagram.len(), HEADER_LEN + 5);
+
+        let packet = decode(&datagram).unwrap();
+        assert_eq!(packet.header, header(Flags::empty()));
+        assert_eq!(packet.payload, b"hello");
+    }
+
+    #[test]
+    fn round_trip_with_crc() {
+        let datagram = encoded(Flags::CRC, b"hello");
+        assert_eq!(datagram.len(), HEADER_LEN + 5 + CRC_LEN);
+
+        let packet = decode(&datagram).unwrap();
+        assert_eq!(packet.header, header(Flags::CRC));
+        assert_eq!(packet.payload, b"hello");
+    }
+
+    #[test]
+    fn round_trip_sealed_payload() {
+        let mut flags = Flags::ENCRYPTED;
+        flags.set(Flags::CRC, true);
+        let sealed = [0xaa; SEAL_LEN + 5];
+        let datagram = encoded(flags, &sealed);
+        assert_eq!(datagram.len(), HEADER_LEN + SEAL_LEN + 5 + CRC_LEN);
+        // The length in the header excludes nonce and tag.
+        assert_eq!(datagram[HEADER_LEN - 2..HEADER_LEN], [0, 5]);
+
+        let packet = decode(&datagram).unwrap();
+        assert_eq!(packet.payload, sealed);
+    }
+
+    #[test]
+    fn round_trip_empty_payload() {
+        let datagram = encoded(Flags::CRC, &[]);
+        let packet = decode(&datagram).unwrap();
+        assert!(packet.payload.is_empty());
+    }
+
+    #[test]
+    fn encode_reuses_buffer() {
+        let mut out = vec![0xff; 100];
+        encode(&header(Flags::empty()), b"abc", &mut out);
+        assert_eq!(decode(&out).unwrap().payload, b"abc");
+    }
+
+    #[test]
+    fn too_short() {
+        let datagram = encoded(Flags::empty(), &[]);
+        assert_eq!(
+            decode(&datagram[..HEADER_LEN - 1]),
+            Err(DecodeError::TooShort(HEADER_LEN - 1))
+        );
+        assert_eq!(decode(&[]), Err(DecodeError::TooShort(0)));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut datagram = encoded(Flags::empty(), b"hello");
+        datagram[0] = b'X';
+        assert!(matches!(decode(&datagram), Err(DecodeError::BadMagic(_))));
+
+        // The legacy format of a bare counter is rejected as well.
+        let legacy = [0u8; HEADER_LEN];
+        assert_eq!(decode(&legacy), Err(DecodeError::BadMagic(0)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut datagram = encoded(Flags::empty(), b"hello");
+        datagram[4] = VERSION + 1;
+        assert_eq!(
+            decode(&datagram),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let datagram = encoded(Flags::empty(), b"hello");
+        assert_eq!(
+            decode(&datagram[..datagram.len() - 1]),
+            Err(DecodeError::LengthMismatch {
+                expected: HEADER_LEN + 5,
+                actual: HEADER_LEN + 4
+            })
+        );
+
+        let mut padded = datagram.clone();
+        padded.push(0);
+        assert!(matches!(
+            decode(&padded),
+            Err(DecodeError::LengthMismatch { .. })
+        ));
+
+        // Stripping the CRC trailer but keeping the flag is detected.
+        let with_crc = encoded(Flags::CRC, b"hello");
+        assert!(matches!(
+            decode(&with_crc[..with_crc.len() - CRC_LEN]),
+            Err(DecodeError::LengthMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut datagram = encoded(Flags::CRC, b"hello");
+        datagram[HEADER_LEN] ^= 0x01;
+        assert!(matches!(
+            decode(&datagram),
+            Err(DecodeError::CrcMismatch { .. })
+        ));
+    }
+}
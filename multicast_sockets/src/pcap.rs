@@ -0,0 +1,424 @@
+//! Capture of received datagrams to pcap files and reading them back.
+//!
+//! Captures use the classic pcap format with nanosecond timestamps and the
+//! raw IP link type. The kernel only hands the UDP payload to the receiver,
+//! so IPv4 or IPv6 and UDP headers are synthesized from the datagram's
+//! source and destination. The reader also accepts microsecond captures and
+//! Ethernet frames, e.g. from `tcpdump`.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+/// Largest record accepted when reading, the snap length `tcpdump` uses.
+const MAX_RECORD_LEN: u32 = 262_144;
+
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const ETHERNET_HEADER_LEN: usize = 14;
+/// TTL or hop limit of the synthesized headers, the received value is not
+/// known.
+const HOP_LIMIT: u8 = 1;
+
+/// Writes datagrams with synthesized IP and UDP headers to a pcap file.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    /// Reused buffer for the synthesized packet.
+    packet: Vec<u8>,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_NANOS.to_ne_bytes());
+        header.extend_from_slice(&2u16.to_ne_bytes());
+        header.extend_from_slice(&4u16.to_ne_bytes());
+        // Time zone offset and timestamp accuracy, both always zero.
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_ne_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_ne_bytes());
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer,
+            packet: Vec::new(),
+        })
+    }
+
+    /// Write a datagram received at `timestamp_ns` since the UNIX epoch.
+    ///
+    /// Fails with `InvalidInput` if the addresses are of different families.
+    pub fn write_datagram(
+        &mut self,
+        timestamp_ns: i64,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        self.packet.clear();
+        synthesize_udp(&mut self.packet, source, destination, payload)?;
+
+        let captured = self.packet.len().min(SNAPLEN as usize);
+        let timestamp_ns = timestamp_ns.max(0) as u64;
+        let mut record = [0; 16];
+        record[0..4].copy_from_slice(&((timestamp_ns / 1_000_000_000) as u32).to_ne_bytes());
+        record[4..8].copy_from_slice(&((timestamp_ns % 1_000_000_000) as u32).to_ne_bytes());
+        record[8..12].copy_from_slice(&(captured as u32).to_ne_bytes());
+        record[12..16].copy_from_slice(&(self.packet.len() as u32).to_ne_bytes());
+        self.writer.write_all(&record)?;
+        self.writer.write_all(&self.packet[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A packet record of a pcap file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Capture time in nanoseconds since the UNIX epoch.
+    pub timestamp_ns: u64,
+    /// The captured link layer frame, possibly truncated.
+    pub data: Vec<u8>,
+}
+
+/// A UDP datagram parsed from a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpDatagram<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+/// Reads the records of a classic pcap file.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read and check the file header.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => {
+                return Err(invalid_data(format!(
+                    "not a pcap file, magic {magic:#010x}"
+                )))
+            }
+        };
+
+        let mut this = Self {
+            reader,
+            swapped,
+            nanos,
+            linktype: 0,
+        };
+        this.linktype = this.u32_at(&header, 20);
+        if ![LINKTYPE_RAW, LINKTYPE_ETHERNET].contains(&this.linktype) {
+            return Err(invalid_data(format!(
+                "unsupported link type {}",
+                this.linktype
+            )));
+        }
+        Ok(this)
+    }
+
+    /// Read the next record, `None` at the end of the file.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let seconds = u64::from(self.u32_at(&header, 0));
+        let fraction = u64::from(self.u32_at(&header, 4));
+        let fraction_ns = match self.nanos {
+            true => fraction,
+            false => fraction * 1000,
+        };
+        let captured = self.u32_at(&header, 8);
+        if captured > MAX_RECORD_LEN {
+            return Err(invalid_data(format!("record of {captured} bytes")));
+        }
+
+        let mut data = vec![0; captured as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            timestamp_ns: seconds * 1_000_000_000 + fraction_ns,
+            data,
+        }))
+    }
+
+    /// Parse the UDP datagram of a record, `None` for other packets.
+    pub fn parse_udp<'a>(&self, record: &'a Record) -> Option<UdpDatagram<'a>> {
+        let packet = match self.linktype {
+            LINKTYPE_ETHERNET => {
+                let ethertype = record.data.get(12..ETHERNET_HEADER_LEN)?;
+                if ethertype != [0x08, 0x00] && ethertype != [0x86, 0xdd] {
+                    return None;
+                }
+                &record.data[ETHERNET_HEADER_LEN..]
+            }
+            _ => &record.data,
+        };
+        parse_ip_udp(packet)
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let value = u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        match self.swapped {
+            true => value.swap_bytes(),
+            false => value,
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Append an IP packet with a UDP header and `payload` to `packet`.
+fn synthesize_udp(
+    packet: &mut Vec<u8>,
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: &[u8],
+) -> io::Result<()> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "datagram too large");
+
+    let pseudo_header = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len = u16::try_from(IPV4_HEADER_LEN + udp_len).map_err(|_| too_large())?;
+            let start = packet.len();
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, no fragmentation.
+            packet.extend_from_slice(&[0, 0, 0x40, 0]);
+            packet.extend_from_slice(&[HOP_LIMIT, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let header_checksum = checksum(&[&packet[start..]]);
+            packet[start + 10..start + 12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pseudo_header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let payload_len = u16::try_from(udp_len).map_err(|_| too_large())?;
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&payload_len.to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, HOP_LIMIT]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            pseudo_header
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination of different address families",
+            ))
+        }
+    };
+
+    let mut udp_header = [0; UDP_HEADER_LEN];
+    udp_header[0..2].copy_from_slice(&source.port().to_be_bytes());
+    udp_header[2..4].copy_from_slice(&destination.port().to_be_bytes());
+    udp_header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    // A zero checksum means none was computed, which is only valid for IPv4.
+    let udp_checksum = match checksum(&[&pseudo_header, &udp_header, payload]) {
+        0 => 0xffff,
+        udp_checksum => udp_checksum,
+    };
+    udp_header[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    packet.extend_from_slice(&udp_header);
+    packet.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Parse an IPv4 or IPv6 packet carrying UDP. IPv6 extension headers and
+/// IPv4 fragments are not supported.
+fn parse_ip_udp(packet: &[u8]) -> Option<UdpDatagram<'_>> {
+    let (src, dst, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let fragmented = u16::from_be_bytes([packet.get(6)? & 0x3f, *packet.get(7)?]) != 0;
+            if packet.get(9)? != &IPPROTO_UDP || header_len < IPV4_HEADER_LEN || fragmented {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                packet.get(header_len..)?,
+            )
+        }
+        6 => {
+            if packet.get(6)? != &IPPROTO_UDP {
+                return None;
+            }
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                packet.get(IPV6_HEADER_LEN..)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let src_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let dst_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    let udp_len = usize::from(u16::from_be_bytes(udp.get(4..6)?.try_into().ok()?));
+    Some(UdpDatagram {
+        source: SocketAddr::new(src, src_port),
+        destination: SocketAddr::new(dst, dst_port),
+        payload: udp.get(UDP_HEADER_LEN..udp_len)?,
+    })
+}
+
+/// Internet checksum over the concatenation of `chunks`, of which only the
+/// last may have an odd length.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        let mut words = chunk.chunks_exact(2);
+        for word in &mut words {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            sum += u32::from(*last) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_back() {
+        let datagrams = [
+            (
+                1_700_000_000_123_456_789,
+                "[fd00::2]:20202".parse().unwrap(),
+                "[ff14::1a]:30000".parse().unwrap(),
+                &b"first"[..],
+            ),
+            (
+                1_700_000_001_000_000_001,
+                "192.0.2.2:20202".parse().unwrap(),
+                "239.1.2.3:30000".parse().unwrap(),
+                &b"odd length"[..],
+            ),
+        ];
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for (timestamp_ns, source, destination, payload) in datagrams {
+            writer
+                .write_datagram(timestamp_ns, source, destination, payload)
+                .unwrap();
+        }
+        let file = writer.into_inner();
+
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        for (timestamp_ns, source, destination, payload) in datagrams {
+            let record = reader.next_record().unwrap().unwrap();
+            assert_eq!(record.timestamp_ns, timestamp_ns as u64);
+            let datagram = reader.parse_udp(&record).unwrap();
+            assert_eq!(datagram.source, source);
+            assert_eq!(datagram.destination, destination);
+            assert_eq!(datagram.payload, payload);
+        }
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn synthesized_checksums_verify() {
+        let mut packet = Vec::new();
+        let source = "192.0.2.2:20202".parse().unwrap();
+        let destination = "239.1.2.3:30000".parse().unwrap();
+        synthesize_udp(&mut packet, source, destination, b"abc").unwrap();
+        assert_eq!(checksum(&[&packet[..IPV4_HEADER_LEN]]), 0);
+        let pseudo_header = [192, 0, 2, 2, 239, 1, 2, 3, 0, IPPROTO_UDP, 0, 11];
+        assert_eq!(checksum(&[&pseudo_header, &packet[IPV4_HEADER_LEN..]]), 0);
+
+        let mut packet = Vec::new();
+        let source = "[fd00::2]:20202".parse().unwrap();
+        let destination = "[ff14::1a]:30000".parse().unwrap();
+        synthesize_udp(&mut packet, source, destination, b"abc").unwrap();
+        let mut pseudo_header = packet[8..IPV6_HEADER_LEN].to_vec();
+        pseudo_header.extend_from_slice(&[0, 0, 0, 11, 0, 0, 0, IPPROTO_UDP]);
+        assert_eq!(checksum(&[&pseudo_header, &packet[IPV6_HEADER_LEN..]]), 0);
+    }
+
+    #[test]
+    fn reads_microsecond_ethernet_captures() {
+        let mut file = Vec::new();
+        file.extend_from_slice(&MAGIC_MICROS.to_be_bytes());
+        file.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(&SNAPLEN.to_be_bytes());
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x86, 0xdd]);
+        let source = "[fd00::2]:20202".parse().unwrap();
+        let destination = "[ff14::1a]:30000".parse().unwrap();
+        synthesize_udp(&mut frame, source, destination, b"payload").unwrap();
+        file.extend_from_slice(&10u32.to_be_bytes());
+        file.extend_from_slice(&20u32.to_be_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        file.extend_from_slice(&frame);
+
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        let record = reader.next_record().unwrap().unwrap();
+        assert_eq!(record.timestamp_ns, 10_000_020_000);
+        let datagram = reader.parse_udp(&record).unwrap();
+        assert_eq!(datagram.destination, destination);
+        assert_eq!(datagram.payload, b"payload");
+    }
+}
@@ -0,0 +1,242 @@
+//! Batched reception of datagrams with their kernel metadata.
+
+use std::{
+    fmt, fs,
+    io::IoSliceMut,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::{FromRawFd, IntoRawFd, OwnedFd, RawFd},
+};
+
+use nix::{
+    cmsg_space,
+    libc::{in6_pktinfo, in_pktinfo},
+    sys::{
+        socket::{
+            bind, getsockopt, recvmmsg, setsockopt, socket, sockopt, AddressFamily,
+            ControlMessageOwned, MsgFlags, MultiHeaders, SockFlag, SockType, SockaddrStorage,
+        },
+        time::TimeVal,
+    },
+};
+
+use crate::{latency, to_socket_addr};
+
+/// Default number of datagrams received with one `recvmmsg` call.
+pub const DEFAULT_FRAMES: usize = 32;
+/// Default maximum size of a received datagram.
+pub const DEFAULT_BUFFER_SIZE: usize = 1400;
+/// Default size of the socket receive buffer requested from the kernel.
+pub const DEFAULT_RECEIVE_BUFFER: usize = 512000;
+
+/// Metadata of a received datagram, taken from the message header and
+/// control messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Address of the sender.
+    pub source: Option<SocketAddr>,
+    /// Destination address of the datagram, i.e. the multicast group.
+    pub group: Option<IpAddr>,
+    /// Index of the interface the datagram was received on.
+    pub interface_index: Option<u32>,
+    /// Kernel receive timestamp in nanoseconds since the UNIX epoch.
+    pub received_ns: Option<i64>,
+    /// Cumulative `SO_RXQ_OVFL` drop counter, only present once the kernel
+    /// dropped datagrams on this socket.
+    pub drop_counter: Option<u32>,
+    /// Original length of a datagram which was larger than the buffer and
+    /// got truncated (`MSG_TRUNC`).
+    pub truncated_from: Option<usize>,
+}
+
+impl Metadata {
+    pub(crate) fn set_ipv4_packet_info(&mut self, info: &in_pktinfo) {
+        let addr = u32::from_be(info.ipi_addr.s_addr);
+        self.group = Some(IpAddr::V4(Ipv4Addr::from(addr)));
+        self.interface_index = Some(info.ipi_ifindex as u32);
+    }
+
+    pub(crate) fn set_ipv6_packet_info(&mut self, info: &in6_pktinfo) {
+        self.group = Some(IpAddr::from(info.ipi6_addr.s6_addr));
+        // Depending on the target platform, this is a `u32` or `i32`.
+        #[allow(clippy::unnecessary_cast)]
+        let interface_index = info.ipi6_ifindex as u32;
+        self.interface_index = Some(interface_index);
+    }
+}
+
+/// Buffer for the control messages enabled by [`bind_socket`].
+pub(crate) fn control_buffer() -> Vec<u8> {
+    cmsg_space!(TimeVal, u32, in6_pktinfo, in_pktinfo)
+}
+
+/// Open a non-blocking UDP socket bound to `port` on the unspecified address
+/// with all options enabled which are required to fill [`Metadata`].
+pub fn bind_socket(family: AddressFamily, port: u16) -> nix::Result<RawFd> {
+    bind_socket_with(family, port, |_| Ok(()))
+}
+
+/// Like [`bind_socket`], but `configure` sets further options on the socket
+/// before it is bound.
+pub fn bind_socket_with(
+    family: AddressFamily,
+    port: u16,
+    configure: impl FnOnce(RawFd) -> nix::Result<()>,
+) -> nix::Result<RawFd> {
+    let socket = socket(family, SockType::Datagram, SockFlag::SOCK_NONBLOCK, None)?;
+    // SAFETY:
+    // The socket was just opened and is not owned by anything else. It is
+    // closed when one of the options can not be set.
+    let owned = unsafe { OwnedFd::from_raw_fd(socket) };
+    configure(socket)?;
+
+    setsockopt(socket, sockopt::ReceiveTimestamp, &true)?;
+    setsockopt(socket, sockopt::ReuseAddr, &true)?;
+    setsockopt(socket, sockopt::RxqOvfl, &1)?;
+
+    // The packet info carries the destination group of each datagram.
+    let bind_ip = match family {
+        AddressFamily::Inet => {
+            setsockopt(socket, sockopt::Ipv4PacketInfo, &true)?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => {
+            setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true)?;
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        }
+    };
+    let bind_addr: SockaddrStorage = SocketAddr::new(bind_ip, port).into();
+    bind(socket, &bind_addr)?;
+
+    Ok(owned.into_raw_fd())
+}
+
+/// Requested and effective size of a socket receive buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiveBuffer {
+    pub requested: usize,
+    /// Size read back from the kernel, which doubles the requested size to
+    /// account for its bookkeeping overhead.
+    pub effective: usize,
+    /// The `net.core.rmem_max` limit for unprivileged sockets, if readable.
+    pub rmem_max: Option<usize>,
+}
+
+impl ReceiveBuffer {
+    /// Whether the kernel granted less than requested due to `rmem_max`.
+    pub fn is_clamped(&self) -> bool {
+        self.effective < self.requested.saturating_mul(2)
+    }
+}
+
+impl fmt::Display for ReceiveBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "requested {} bytes, kernel uses {} bytes",
+            self.requested, self.effective
+        )?;
+        if let Some(rmem_max) = self.rmem_max {
+            write!(f, " (net.core.rmem_max {rmem_max})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Set the socket receive buffer size and read back what the kernel
+/// actually uses.
+pub fn set_receive_buffer(socket: RawFd, size: usize) -> nix::Result<ReceiveBuffer> {
+    setsockopt(socket, sockopt::RcvBuf, &size)?;
+    Ok(ReceiveBuffer {
+        requested: size,
+        effective: getsockopt(socket, sockopt::RcvBuf)?,
+        rmem_max: rmem_max(),
+    })
+}
+
+/// The largest receive buffer unprivileged sockets may request.
+pub fn rmem_max() -> Option<usize> {
+    fs::read_to_string("/proc/sys/net/core/rmem_max")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Receives batches of datagrams with `recvmmsg` into preallocated buffers.
+#[derive(Debug)]
+pub struct BatchReceiver {
+    frames: usize,
+    buffer_size: usize,
+    buffers: Vec<u8>,
+}
+
+impl BatchReceiver {
+    /// Create a receiver for batches of up to `frames` datagrams with up to
+    /// `buffer_size` bytes each.
+    pub fn new(frames: usize, buffer_size: usize) -> Self {
+        Self {
+            frames,
+            buffer_size,
+            buffers: vec![0; frames * buffer_size],
+        }
+    }
+
+    /// Receive one batch with a single `recvmmsg` call and pass every
+    /// datagram to `handle`. Returns the number of received datagrams.
+    ///
+    /// Datagrams larger than the buffer size are passed truncated, with
+    /// their original length in [`Metadata::truncated_from`].
+    pub fn recv(
+        &mut self,
+        socket: RawFd,
+        mut handle: impl FnMut(&[u8], &Metadata),
+    ) -> nix::Result<usize> {
+        let iovs: Vec<_> = self
+            .buffers
+            .chunks_mut(self.buffer_size)
+            .map(|buf| [IoSliceMut::new(buf)])
+            .collect();
+
+        // nix does not reset `msg_controllen` between `recvmmsg` calls, so
+        // the headers are recreated to not truncate control messages which
+        // are larger than in the previous call (e.g. once `RxqOvfl` appears).
+        let mut headers =
+            MultiHeaders::<SockaddrStorage>::preallocate(self.frames, Some(control_buffer()));
+
+        // With `MSG_TRUNC` the kernel reports the original length of
+        // truncated datagrams instead of the received one.
+        let results = recvmmsg(socket, &mut headers, iovs.iter(), MsgFlags::MSG_TRUNC, None)?;
+
+        let mut received = 0;
+        for recv_msg in results {
+            received += 1;
+
+            let mut meta = Metadata {
+                source: recv_msg.address.as_ref().and_then(to_socket_addr),
+                truncated_from: recv_msg
+                    .flags
+                    .contains(MsgFlags::MSG_TRUNC)
+                    .then_some(recv_msg.bytes),
+                ..Default::default()
+            };
+            for cmsg in recv_msg.cmsgs() {
+                match cmsg {
+                    ControlMessageOwned::Ipv6PacketInfo(info) => meta.set_ipv6_packet_info(&info),
+                    ControlMessageOwned::Ipv4PacketInfo(info) => meta.set_ipv4_packet_info(&info),
+                    ControlMessageOwned::ScmTimestamp(tv) => {
+                        meta.received_ns = Some(latency::timeval_to_nanos(&tv));
+                    }
+                    ControlMessageOwned::RxqOvfl(counter) => {
+                        meta.drop_counter = Some(counter);
+                    }
+                    _ => {}
+                }
+            }
+
+            let payload = recv_msg.iovs().next().unwrap_or_default();
+            handle(payload, &meta);
+        }
+
+        Ok(received)
+    }
+}
@@ -0,0 +1,237 @@
+//! Multicast scopes of group addresses.
+//!
+//! IPv6 groups carry their scope in the 4-bit scope field (RFC 4291 section
+//! 2.7). Groups of the interface-local and link-local scope exist once per
+//! interface, so socket addresses of them need the interface index as scope
+//! id, which the kernel otherwise can not infer on hosts with several
+//! interfaces. IPv4 has no scope field, the equivalent scopes are address
+//! ranges (RFC 5771, RFC 2365).
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Scope of a multicast group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Only loopback on one interface, `ff01::/16`.
+    InterfaceLocal,
+    /// `ff02::/16` or `224.0.0.0/24`, not forwarded by routers.
+    LinkLocal,
+    /// `ff03::/16`.
+    RealmLocal,
+    /// `ff04::/16` or `239.0.0.0/8` outside the narrower ranges.
+    AdminLocal,
+    /// `ff05::/16` or the IPv4 local scope `239.255.0.0/16`.
+    SiteLocal,
+    /// `ff08::/16` or `239.192.0.0/14`.
+    OrganizationLocal,
+    Global,
+    /// An IPv6 scope value without assigned meaning, available to
+    /// administrators to define further regions.
+    Unassigned(u8),
+}
+
+impl Scope {
+    /// Whether groups of this scope need an interface as scope id.
+    pub fn is_zoned(self) -> bool {
+        matches!(self, Self::InterfaceLocal | Self::LinkLocal)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InterfaceLocal => write!(f, "interface-local"),
+            Self::LinkLocal => write!(f, "link-local"),
+            Self::RealmLocal => write!(f, "realm-local"),
+            Self::AdminLocal => write!(f, "admin-local"),
+            Self::SiteLocal => write!(f, "site-local"),
+            Self::OrganizationLocal => write!(f, "organization-local"),
+            Self::Global => write!(f, "global"),
+            Self::Unassigned(scope) => write!(f, "unassigned scope {scope:x}"),
+        }
+    }
+}
+
+/// An address is not usable as multicast group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupError {
+    NotMulticast(IpAddr),
+    /// The scope values 0 and `f` are reserved.
+    ReservedScope(Ipv6Addr),
+    /// The scope id of a zoned group differs from the selected interface.
+    ScopeIdMismatch {
+        group: SocketAddr,
+        interface_index: u32,
+    },
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotMulticast(IpAddr::V4(addr)) => {
+                write!(f, "{addr} is not a multicast address (224.0.0.0/4)")
+            }
+            Self::NotMulticast(IpAddr::V6(addr)) => {
+                write!(f, "{addr} is not a multicast address (ff00::/8)")
+            }
+            Self::ReservedScope(addr) => {
+                write!(
+                    f,
+                    "{addr} has the reserved multicast scope {:x}",
+                    addr.octets()[1] & 0xf
+                )
+            }
+            Self::ScopeIdMismatch {
+                group,
+                interface_index,
+            } => write!(
+                f,
+                "{group} is scoped to another interface than the selected one with index \
+                 {interface_index}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GroupError {}
+
+/// The scope of `group`, or why it is no usable multicast group.
+pub fn multicast_scope(group: &IpAddr) -> Result<Scope, GroupError> {
+    match group {
+        IpAddr::V4(group) => ipv4_scope(group),
+        IpAddr::V6(group) => ipv6_scope(group),
+    }
+}
+
+/// `group` with the scope id set to `interface_index` if its scope is zoned.
+///
+/// A scope id given with the group (e.g. `[ff02::1%2]:30000`) has to match
+/// the interface. Other groups are returned unchanged.
+pub fn scoped_group(group: SocketAddr, interface_index: u32) -> Result<SocketAddr, GroupError> {
+    let SocketAddr::V6(mut addr) = group else {
+        multicast_scope(&group.ip())?;
+        return Ok(group);
+    };
+    if !ipv6_scope(addr.ip())?.is_zoned() {
+        return Ok(group);
+    }
+    match addr.scope_id() {
+        0 => addr.set_scope_id(interface_index),
+        scope_id if scope_id != interface_index => {
+            return Err(GroupError::ScopeIdMismatch {
+                group,
+                interface_index,
+            })
+        }
+        _ => {}
+    }
+    Ok(SocketAddr::V6(addr))
+}
+
+fn ipv4_scope(group: &Ipv4Addr) -> Result<Scope, GroupError> {
+    if !group.is_multicast() {
+        return Err(GroupError::NotMulticast(IpAddr::V4(*group)));
+    }
+    let scope = match group.octets() {
+        [224, 0, 0, _] => Scope::LinkLocal,
+        [239, 255, ..] => Scope::SiteLocal,
+        [239, second, ..] if second & 0xfc == 192 => Scope::OrganizationLocal,
+        [239, ..] => Scope::AdminLocal,
+        _ => Scope::Global,
+    };
+    Ok(scope)
+}
+
+fn ipv6_scope(group: &Ipv6Addr) -> Result<Scope, GroupError> {
+    if !group.is_multicast() {
+        return Err(GroupError::NotMulticast(IpAddr::V6(*group)));
+    }
+    let scope = match group.octets()[1] & 0xf {
+        0x0 | 0xf => return Err(GroupError::ReservedScope(*group)),
+        0x1 => Scope::InterfaceLocal,
+        0x2 => Scope::LinkLocal,
+        0x3 => Scope::RealmLocal,
+        0x4 => Scope::AdminLocal,
+        0x5 => Scope::SiteLocal,
+        0x8 => Scope::OrganizationLocal,
+        0xe => Scope::Global,
+        scope => Scope::Unassigned(scope),
+    };
+    Ok(scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(group: &str) -> Result<Scope, GroupError> {
+        multicast_scope(&group.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv6_scopes() {
+        assert_eq!(scope("ff01::1"), Ok(Scope::InterfaceLocal));
+        assert_eq!(scope("ff02::1"), Ok(Scope::LinkLocal));
+        // The scope is independent of the flags.
+        assert_eq!(scope("ff32::1"), Ok(Scope::LinkLocal));
+        assert_eq!(scope("ff14::1a"), Ok(Scope::AdminLocal));
+        assert_eq!(scope("ff05::2"), Ok(Scope::SiteLocal));
+        assert_eq!(scope("ff08::1"), Ok(Scope::OrganizationLocal));
+        assert_eq!(scope("ff3e::1"), Ok(Scope::Global));
+        assert_eq!(scope("ff06::1"), Ok(Scope::Unassigned(6)));
+        assert_eq!(
+            scope("ff00::1"),
+            Err(GroupError::ReservedScope("ff00::1".parse().unwrap()))
+        );
+        assert!(scope("ff1f::1").is_err());
+        assert_eq!(
+            scope("fe80::1"),
+            Err(GroupError::NotMulticast("fe80::1".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn ipv4_scopes() {
+        assert_eq!(scope("224.0.0.251"), Ok(Scope::LinkLocal));
+        assert_eq!(scope("239.255.10.1"), Ok(Scope::SiteLocal));
+        assert_eq!(scope("239.193.0.1"), Ok(Scope::OrganizationLocal));
+        assert_eq!(scope("239.1.2.3"), Ok(Scope::AdminLocal));
+        assert_eq!(scope("232.1.1.1"), Ok(Scope::Global));
+        assert_eq!(
+            scope("192.0.2.1"),
+            Err(GroupError::NotMulticast("192.0.2.1".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn scope_id_of_zoned_groups() {
+        let group: SocketAddr = "[ff02::1]:30000".parse().unwrap();
+        assert_eq!(
+            scoped_group(group, 3),
+            Ok("[ff02::1%3]:30000".parse().unwrap())
+        );
+        assert_eq!(
+            scoped_group(group, 3).unwrap().to_string(),
+            "[ff02::1%3]:30000"
+        );
+
+        let scoped: SocketAddr = "[ff02::1%3]:30000".parse().unwrap();
+        assert_eq!(scoped_group(scoped, 3), Ok(scoped));
+        assert_eq!(
+            scoped_group(scoped, 2),
+            Err(GroupError::ScopeIdMismatch {
+                group: scoped,
+                interface_index: 2
+            })
+        );
+
+        for group in ["[ff14::1a]:30000", "239.255.10.1:30000"] {
+            let group: SocketAddr = group.parse().unwrap();
+            assert_eq!(scoped_group(group, 3), Ok(group));
+        }
+        assert!(scoped_group("[fe80::1]:30000".parse().unwrap(), 3).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Batched sending of datagrams to a multicast group.
+
+use std::{
+    io::IoSlice,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::{FromRawFd, IntoRawFd, OwnedFd, RawFd},
+};
+
+use nix::{
+    cmsg_space,
+    libc::{self, in6_addr, in6_pktinfo, in_addr, in_pktinfo},
+    sys::socket::{
+        bind, sendmmsg, socket, AddressFamily, ControlMessage, MsgFlags, MultiHeaders, SockFlag,
+        SockType, SockaddrStorage,
+    },
+};
+
+use crate::{get_int_option, set_option};
+
+/// Packet info to select the outgoing interface and source address.
+#[derive(Clone, Copy)]
+pub enum PacketInfo {
+    V4(in_pktinfo),
+    V6(in6_pktinfo),
+}
+
+impl PacketInfo {
+    /// Send from `src_addr` on the interface with `interface_index`.
+    pub fn new(src_addr: IpAddr, interface_index: u32) -> Self {
+        match src_addr {
+            IpAddr::V4(src_addr) => PacketInfo::V4(in_pktinfo {
+                ipi_ifindex: interface_index as i32,
+                ipi_spec_dst: in_addr {
+                    s_addr: u32::from(src_addr).to_be(),
+                },
+                ipi_addr: in_addr { s_addr: 0 },
+            }),
+            IpAddr::V6(src_addr) => PacketInfo::V6(in6_pktinfo {
+                ipi6_addr: in6_addr {
+                    s6_addr: src_addr.octets(),
+                },
+                // Depending on the target platform, this is a `u32` or `i32`.
+                ipi6_ifindex: interface_index as _,
+            }),
+        }
+    }
+
+    pub fn as_control_message(&self) -> ControlMessage<'_> {
+        match self {
+            PacketInfo::V4(info) => ControlMessage::Ipv4PacketInfo(info),
+            PacketInfo::V6(info) => ControlMessage::Ipv6PacketInfo(info),
+        }
+    }
+
+    /// Control message buffer of exactly the encoded size. A larger buffer
+    /// would be passed to the kernel as a trailing invalid control message.
+    fn cmsg_space(&self) -> Vec<u8> {
+        match self {
+            PacketInfo::V4(_) => cmsg_space!(in_pktinfo),
+            PacketInfo::V6(_) => cmsg_space!(in6_pktinfo),
+        }
+    }
+}
+
+/// Open a non-blocking UDP socket bound to `port` on the unspecified address.
+pub fn bind_socket(family: AddressFamily, port: u16) -> nix::Result<RawFd> {
+    let socket = socket(family, SockType::Datagram, SockFlag::SOCK_NONBLOCK, None)?;
+    // SAFETY:
+    // The socket was just opened and is not owned by anything else. It is
+    // closed when it can not be bound.
+    let owned = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    let bind_ip = match family {
+        AddressFamily::Inet => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        _ => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let bind_addr: SockaddrStorage = SocketAddr::new(bind_ip, port).into();
+    bind(socket, &bind_addr)?;
+
+    Ok(owned.into_raw_fd())
+}
+
+/// Set the TTL (IPv4) or hop limit (IPv6) of outgoing multicast datagrams,
+/// which limits how many routers forward them. The kernel default is 1.
+pub fn set_multicast_hops(socket: RawFd, family: AddressFamily, hops: u8) -> nix::Result<()> {
+    let (level, name) = match family {
+        AddressFamily::Inet => (libc::IPPROTO_IP, libc::IP_MULTICAST_TTL),
+        _ => (libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS),
+    };
+    set_option(socket, level, name, &libc::c_int::from(hops))
+}
+
+/// TTL or hop limit of outgoing multicast datagrams.
+pub fn multicast_hops(socket: RawFd, family: AddressFamily) -> nix::Result<u8> {
+    let hops: libc::c_int = match family {
+        AddressFamily::Inet => get_int_option(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_TTL)?,
+        _ => get_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS)?,
+    };
+    Ok(hops as u8)
+}
+
+/// Set whether outgoing multicast datagrams are looped back to sockets on
+/// this host which joined the group. The kernel default is enabled.
+pub fn set_multicast_loop(socket: RawFd, family: AddressFamily, enable: bool) -> nix::Result<()> {
+    let (level, name) = match family {
+        AddressFamily::Inet => (libc::IPPROTO_IP, libc::IP_MULTICAST_LOOP),
+        _ => (libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_LOOP),
+    };
+    set_option(socket, level, name, &libc::c_int::from(enable))
+}
+
+/// Whether outgoing multicast datagrams are looped back.
+pub fn multicast_loop(socket: RawFd, family: AddressFamily) -> nix::Result<bool> {
+    let enabled: libc::c_int = match family {
+        AddressFamily::Inet => get_int_option(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_LOOP)?,
+        _ => get_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_LOOP)?,
+    };
+    Ok(enabled != 0)
+}
+
+/// Set the IPv4 TOS or IPv6 traffic class byte of outgoing datagrams. The
+/// DSCP are its upper six bits, see [`dscp_to_traffic_class`].
+pub fn set_traffic_class(socket: RawFd, family: AddressFamily, class: u8) -> nix::Result<()> {
+    let (level, name) = match family {
+        AddressFamily::Inet => (libc::IPPROTO_IP, libc::IP_TOS),
+        _ => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+    set_option(socket, level, name, &libc::c_int::from(class))
+}
+
+/// TOS or traffic class byte of outgoing datagrams.
+pub fn traffic_class(socket: RawFd, family: AddressFamily) -> nix::Result<u8> {
+    let class: libc::c_int = match family {
+        AddressFamily::Inet => get_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS)?,
+        _ => get_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?,
+    };
+    Ok(class as u8)
+}
+
+/// Traffic class byte for a DSCP with the ECN bits cleared.
+pub fn dscp_to_traffic_class(dscp: u8) -> u8 {
+    dscp << 2
+}
+
+/// Sends batches of datagrams to one destination with `sendmmsg`.
+pub struct BatchSender {
+    socket: RawFd,
+    destination: SockaddrStorage,
+    packet_info: PacketInfo,
+}
+
+impl BatchSender {
+    pub fn new(socket: RawFd, destination: SocketAddr, packet_info: PacketInfo) -> Self {
+        Self {
+            socket,
+            destination: destination.into(),
+            packet_info,
+        }
+    }
+
+    pub fn socket(&self) -> RawFd {
+        self.socket
+    }
+
+    /// Send all `datagrams` with a single `sendmmsg` call. Returns the number
+    /// of sent datagrams, which is smaller than requested if the socket send
+    /// buffer filled up.
+    pub fn send<D: AsRef<[u8]>>(&self, datagrams: &[D]) -> nix::Result<usize> {
+        if datagrams.is_empty() {
+            return Ok(0);
+        }
+
+        let slices: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| [IoSlice::new(datagram.as_ref())])
+            .collect();
+        let addrs = vec![Some(self.destination); datagrams.len()];
+        let cmsgs = [self.packet_info.as_control_message()];
+
+        let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(
+            datagrams.len(),
+            Some(self.packet_info.cmsg_space()),
+        );
+        let results = sendmmsg(
+            self.socket,
+            &mut headers,
+            &slices,
+            addrs,
+            cmsgs,
+            MsgFlags::empty(),
+        )?;
+
+        Ok(results.count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    use nix::{errno::Errno, net::if_::if_nametoindex, unistd::close};
+
+    use super::*;
+    use crate::{
+        membership::{self, Membership},
+        receive::{self, BatchReceiver},
+    };
+
+    #[test]
+    fn socket_options_read_back() {
+        for family in [AddressFamily::Inet, AddressFamily::Inet6] {
+            let socket = bind_socket(family, 0).unwrap();
+
+            set_multicast_hops(socket, family, 7).unwrap();
+            assert_eq!(multicast_hops(socket, family), Ok(7));
+            set_multicast_loop(socket, family, false).unwrap();
+            assert_eq!(multicast_loop(socket, family), Ok(false));
+            set_traffic_class(socket, family, dscp_to_traffic_class(46)).unwrap();
+            assert_eq!(traffic_class(socket, family), Ok(0xb8));
+
+            close(socket).unwrap();
+        }
+    }
+
+    /// IPv4 only, as Linux does not route IPv6 multicast via `lo`.
+    #[test]
+    fn loopback_delivery_on_lo() {
+        let lo = if_nametoindex("lo").unwrap();
+        let group = SocketAddr::new(Ipv4Addr::new(239, 255, 10, 2).into(), 30102);
+        let src_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let receiver = receive::bind_socket(AddressFamily::Inet, group.port()).unwrap();
+        membership::join(receiver, &Membership::any_source(group.ip(), lo)).unwrap();
+
+        let socket = bind_socket(AddressFamily::Inet, 0).unwrap();
+        set_multicast_loop(socket, AddressFamily::Inet, true).unwrap();
+        set_multicast_hops(socket, AddressFamily::Inet, 1).unwrap();
+        let sender = BatchSender::new(socket, group, PacketInfo::new(src_addr, lo));
+        assert_eq!(sender.send(&[b"first", b"other"]), Ok(2));
+
+        let mut batch = BatchReceiver::new(4, 64);
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while received.len() < 2 && Instant::now() < deadline {
+            let result = batch.recv(receiver, |payload, meta| {
+                received.push((payload.to_vec(), *meta));
+            });
+            if result == Err(Errno::EAGAIN) {
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, b"first");
+        assert_eq!(received[1].0, b"other");
+        for (_, meta) in &received {
+            assert_eq!(meta.group, Some(group.ip()));
+            assert_eq!(meta.interface_index, Some(lo));
+            assert_eq!(meta.source.map(|source| source.ip()), Some(src_addr));
+        }
+
+        close(socket).unwrap();
+        close(receiver).unwrap();
+    }
+}
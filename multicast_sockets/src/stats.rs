@@ -0,0 +1,423 @@
+//! Sequence number tracking to detect lost, duplicated and reordered packets.
+
+use std::{collections::VecDeque, fmt};
+
+/// Number of sequence numbers behind the highest received one for which
+/// missing packets are remembered. Packets arriving later than this are
+/// counted as lost (and their late arrival as a duplicate).
+const REORDER_WINDOW: u32 = 1024;
+
+/// Classification of a received sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// First packet seen on this stream.
+    First,
+    /// Next expected sequence number.
+    InOrder,
+    /// Packet arrived after a gap of the given number of missing packets.
+    Gap(u32),
+    /// Packet was already received before.
+    Duplicate,
+    /// Previously missing packet arrived late by `depth` sequence numbers.
+    Reordered { depth: u32 },
+    /// Sequence number jumped far backwards, the sender was likely restarted.
+    Restart,
+}
+
+/// Tracks the sequence numbers of a single stream.
+#[derive(Debug, Default, Clone)]
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    /// Missing sequence numbers within the reorder window, oldest first.
+    missing: VecDeque<u32>,
+    stats: SequenceStats,
+}
+
+/// Counters collected by a [`SequenceTracker`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceStats {
+    /// Unique packets received.
+    pub received: u64,
+    /// Packets which were never received (so far).
+    pub lost: u64,
+    /// Packets received more than once.
+    pub duplicates: u64,
+    /// Packets received after a packet with a higher sequence number.
+    pub reordered: u64,
+    /// Number of detected sender restarts.
+    pub restarts: u64,
+    /// Lost packets which were reconstructed by FEC or retransmitted after
+    /// a NACK. They are not counted as received nor lost.
+    pub recovered: u64,
+    /// Largest number of consecutive missing packets.
+    pub longest_gap: u32,
+    /// Largest distance a reordered packet arrived behind the highest one.
+    pub max_reorder_depth: u32,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the arrival of `seq` and classify it.
+    pub fn record(&mut self, seq: u32) -> Arrival {
+        let Some(highest) = self.highest else {
+            self.highest = Some(seq);
+            self.stats.received += 1;
+            return Arrival::First;
+        };
+
+        // Interpret the distance as signed to handle wrapping counters.
+        let distance = seq.wrapping_sub(highest) as i32;
+        match distance {
+            1.. => {
+                self.stats.received += 1;
+                match self.advance(seq, distance as u32 - 1) {
+                    0 => Arrival::InOrder,
+                    gap => Arrival::Gap(gap),
+                }
+            }
+            0 => {
+                self.stats.duplicates += 1;
+                Arrival::Duplicate
+            }
+            _ => {
+                let depth = distance.unsigned_abs();
+                if depth > REORDER_WINDOW.saturating_mul(4) {
+                    self.highest = Some(seq);
+                    self.missing.clear();
+                    self.stats.received += 1;
+                    self.stats.restarts += 1;
+                    return Arrival::Restart;
+                }
+
+                match self.missing.iter().position(|&missing| missing == seq) {
+                    Some(idx) => {
+                        self.missing.remove(idx);
+                        self.stats.received += 1;
+                        self.stats.lost -= 1;
+                        self.stats.reordered += 1;
+                        self.stats.max_reorder_depth = self.stats.max_reorder_depth.max(depth);
+                        Arrival::Reordered { depth }
+                    }
+                    None => {
+                        self.stats.duplicates += 1;
+                        Arrival::Duplicate
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record that the missing `seq` was reconstructed instead of received.
+    /// Returns `false` if it was not missing within the reorder window.
+    ///
+    /// A `seq` beyond the highest received one, e.g. the reconstructed last
+    /// packet of an FEC span, moves the highest one forward like a received
+    /// packet.
+    pub fn recover(&mut self, seq: u32) -> bool {
+        let distance = self
+            .highest
+            .map_or(1, |highest| seq.wrapping_sub(highest) as i32);
+        if distance > 0 {
+            self.stats.recovered += 1;
+            self.advance(seq, distance as u32 - 1);
+            return true;
+        }
+
+        match self.missing.iter().position(|&missing| missing == seq) {
+            Some(idx) => {
+                self.missing.remove(idx);
+                self.stats.lost -= 1;
+                self.stats.recovered += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Current statistics of this stream.
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+
+    /// Move the highest sequence number forward to `seq`, which follows
+    /// `gap` missing packets, and return the gap.
+    fn advance(&mut self, seq: u32, gap: u32) -> u32 {
+        self.highest = Some(seq);
+        self.evict_outdated(seq);
+        if gap == 0 {
+            return 0;
+        }
+
+        self.stats.lost += u64::from(gap);
+        self.stats.longest_gap = self.stats.longest_gap.max(gap);
+        // Only remember the missing packets which can still arrive within
+        // the reorder window.
+        let remembered = gap.min(REORDER_WINDOW);
+        self.missing
+            .extend((1..=remembered).rev().map(|back| seq.wrapping_sub(back)));
+        gap
+    }
+
+    fn evict_outdated(&mut self, highest: u32) {
+        while let Some(&oldest) = self.missing.front() {
+            if highest.wrapping_sub(oldest) <= REORDER_WINDOW {
+                break;
+            }
+            self.missing.pop_front();
+        }
+    }
+}
+
+impl SequenceStats {
+    /// Fraction of packets lost out of all packets the sender emitted.
+    pub fn loss_rate(&self) -> f64 {
+        let expected = self.received + self.lost + self.recovered;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / expected as f64
+    }
+
+    /// Add the statistics of another stream, e.g. to aggregate all streams
+    /// of a group.
+    pub fn merge(&mut self, other: &SequenceStats) {
+        self.received += other.received;
+        self.lost += other.lost;
+        self.duplicates += other.duplicates;
+        self.reordered += other.reordered;
+        self.restarts += other.restarts;
+        self.recovered += other.recovered;
+        self.longest_gap = self.longest_gap.max(other.longest_gap);
+        self.max_reorder_depth = self.max_reorder_depth.max(other.max_reorder_depth);
+    }
+}
+
+impl fmt::Display for SequenceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {}, lost {} ({:.3}%), duplicates {}, reordered {}, longest gap {}, max reorder depth {}",
+            self.received,
+            self.lost,
+            self.loss_rate() * 100.0,
+            self.duplicates,
+            self.reordered,
+            self.longest_gap,
+            self.max_reorder_depth,
+        )?;
+        if self.recovered > 0 {
+            write!(f, ", recovered {}", self.recovered)?;
+        }
+        if self.restarts > 0 {
+            write!(f, ", restarts {}", self.restarts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the cumulative drop counter the kernel reports via `SO_RXQ_OVFL`.
+///
+/// These are datagrams which reached the host but were dropped because the
+/// socket receive buffer was full. They show up as sequence gaps as well, but
+/// point to a too small `SO_RCVBUF` or a too slow reader instead of loss on
+/// the network.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelDrops {
+    last: u32,
+    total: u64,
+}
+
+impl KernelDrops {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update with the latest cumulative counter and return the number of
+    /// drops since the previous update.
+    pub fn update(&mut self, counter: u32) -> u32 {
+        let delta = counter.wrapping_sub(self.last);
+        self.last = counter;
+        self.total += u64::from(delta);
+        delta
+    }
+
+    /// Total number of datagrams dropped by the kernel.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Drops which were counted elsewhere, e.g. over several sockets.
+    pub fn from_total(total: u64) -> Self {
+        Self { last: 0, total }
+    }
+
+    /// Split the sequence losses of all streams into drops in the socket
+    /// buffer and the remaining (network) loss.
+    pub fn attribute(&self, sequence_lost: u64) -> (u64, u64) {
+        let buffer = self.total.min(sequence_lost);
+        (buffer, sequence_lost - buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_all(tracker: &mut SequenceTracker, sequences: &[u32]) -> Vec<Arrival> {
+        sequences.iter().map(|&seq| tracker.record(seq)).collect()
+    }
+
+    #[test]
+    fn classifies_gaps_duplicates_and_reordering() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(
+            record_all(&mut tracker, &[0, 1, 4, 2, 2, 4, 5]),
+            [
+                Arrival::First,
+                Arrival::InOrder,
+                Arrival::Gap(2),
+                Arrival::Reordered { depth: 2 },
+                Arrival::Duplicate,
+                Arrival::Duplicate,
+                Arrival::InOrder,
+            ]
+        );
+        assert_eq!(
+            tracker.stats(),
+            SequenceStats {
+                received: 5,
+                lost: 1,
+                duplicates: 2,
+                reordered: 1,
+                longest_gap: 2,
+                max_reorder_depth: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn packets_behind_the_reorder_window_stay_lost() {
+        let mut tracker = SequenceTracker::new();
+        tracker.record(0);
+        assert_eq!(tracker.record(2000), Arrival::Gap(1999));
+        // Only the last REORDER_WINDOW missing packets are remembered.
+        assert_eq!(tracker.record(5), Arrival::Duplicate);
+        assert_eq!(
+            tracker.record(2000 - REORDER_WINDOW),
+            Arrival::Reordered {
+                depth: REORDER_WINDOW
+            }
+        );
+        let stats = tracker.stats();
+        assert_eq!(
+            (stats.lost, stats.reordered, stats.duplicates),
+            (1998, 1, 1)
+        );
+    }
+
+    #[test]
+    fn detects_restarts() {
+        let mut tracker = SequenceTracker::new();
+        record_all(&mut tracker, &[10_000, 10_002]);
+        // Within four reorder windows a backwards jump is a late packet.
+        assert_eq!(
+            tracker.record(10_002 - 4 * REORDER_WINDOW),
+            Arrival::Duplicate
+        );
+        assert_eq!(tracker.record(0), Arrival::Restart);
+        assert_eq!(tracker.record(1), Arrival::InOrder);
+        // The packet missing before the restart is no longer expected.
+        assert_eq!(tracker.record(10_001), Arrival::Gap(9_999));
+
+        let stats = tracker.stats();
+        assert_eq!((stats.restarts, stats.lost, stats.received), (1, 10_000, 5));
+    }
+
+    #[test]
+    fn handles_wrapping_sequence_numbers() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(
+            record_all(&mut tracker, &[u32::MAX - 1, u32::MAX, 0, 2, 1]),
+            [
+                Arrival::First,
+                Arrival::InOrder,
+                Arrival::InOrder,
+                Arrival::Gap(1),
+                Arrival::Reordered { depth: 1 },
+            ]
+        );
+        assert_eq!(tracker.stats().lost, 0);
+    }
+
+    #[test]
+    fn recovers_missing_packets() {
+        let mut tracker = SequenceTracker::new();
+        record_all(&mut tracker, &[0, 3]);
+        assert!(tracker.recover(1));
+        // Neither recovered twice nor received after being recovered.
+        assert!(!tracker.recover(1));
+        assert_eq!(tracker.record(1), Arrival::Duplicate);
+        // Received packets are not missing.
+        assert!(!tracker.recover(3));
+
+        let stats = tracker.stats();
+        assert_eq!((stats.lost, stats.recovered, stats.received), (1, 1, 2));
+        assert_eq!(stats.loss_rate(), 0.25);
+    }
+
+    #[test]
+    fn recovers_packets_beyond_the_highest() {
+        // The tail of an FEC span is reconstructed before the next packet.
+        let mut tracker = SequenceTracker::new();
+        record_all(&mut tracker, &[0, 1, 2]);
+        assert!(tracker.recover(3));
+        assert_eq!(tracker.record(4), Arrival::InOrder);
+        assert_eq!(tracker.record(3), Arrival::Duplicate);
+
+        // Packets before a reconstructed one are missing.
+        assert!(tracker.recover(7));
+        assert_eq!(tracker.record(5), Arrival::Reordered { depth: 2 });
+        assert!(tracker.recover(6));
+        assert_eq!(tracker.record(8), Arrival::InOrder);
+
+        let stats = tracker.stats();
+        assert_eq!((stats.lost, stats.recovered, stats.received), (0, 3, 6));
+        assert_eq!(stats.longest_gap, 2);
+
+        // Nothing received yet.
+        let mut tracker = SequenceTracker::new();
+        assert!(tracker.recover(9));
+        assert_eq!(tracker.record(10), Arrival::InOrder);
+    }
+
+    #[test]
+    fn counts_kernel_drops() {
+        let mut drops = KernelDrops::new();
+        // The counter starts with the socket, the first value is all drops.
+        assert_eq!(drops.update(5), 5);
+        assert_eq!(drops.update(5), 0);
+        assert_eq!(drops.update(8), 3);
+        assert_eq!(drops.total(), 8);
+
+        // The 32 bit counter wraps around.
+        let mut drops = KernelDrops::new();
+        drops.update(u32::MAX - 1);
+        assert_eq!(drops.update(2), 4);
+        assert_eq!(drops.total(), u64::from(u32::MAX) + 3);
+    }
+
+    #[test]
+    fn attributes_loss_to_kernel_drops() {
+        let drops = KernelDrops::from_total(10);
+        // Up to the kernel drops, sequence loss happened in the buffer.
+        assert_eq!(drops.attribute(25), (10, 15));
+        assert_eq!(drops.attribute(10), (10, 0));
+        // Drops of datagrams of other streams do not exceed the loss.
+        assert_eq!(drops.attribute(4), (4, 0));
+        assert_eq!(KernelDrops::new().attribute(7), (0, 7));
+    }
+}
@@ -0,0 +1,329 @@
+//! Reception of datagrams with multishot `recvmsg` on io_uring.
+//!
+//! A single multishot request keeps receiving datagrams into buffers which
+//! the kernel takes from a ring of provided buffers, so unlike with
+//! [`BatchReceiver`](crate::receive::BatchReceiver) there is no syscall per
+//! batch while datagrams arrive, and waiting for them needs no polling. This
+//! requires Linux 6.1.
+
+use std::{
+    alloc::{self, Layout},
+    io,
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    os::fd::RawFd,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use io_uring::{
+    cqueue, opcode,
+    types::{BufRingEntry, Fd, RecvMsgOut, SubmitArgs, Timespec},
+    IoUring,
+};
+use nix::{
+    errno::Errno,
+    libc::{self, in6_pktinfo, in_pktinfo},
+    sys::{
+        socket::{SockaddrLike, SockaddrStorage},
+        time::TimeVal,
+    },
+};
+
+use crate::{
+    latency,
+    receive::{self, Metadata},
+    to_socket_addr,
+};
+
+/// Id of the only buffer group.
+const BUFFER_GROUP: u16 = 0;
+/// Size of the `struct io_uring_recvmsg_out` at the start of every buffer.
+const RECVMSG_OUT_LEN: usize = 16;
+/// The kernel limits buffer rings to 2^15 entries.
+const MAX_BUFFERS: usize = 1 << 15;
+
+/// Receives datagrams with a multishot `recvmsg` into a provided buffer ring.
+pub struct UringReceiver {
+    // The ring is dropped first, which cancels the request using the buffers.
+    ring: IoUring,
+    buffer_ring: BufferRing,
+    socket: RawFd,
+    /// Name and control lengths for the multishot request, which the kernel
+    /// reserves at the start of every buffer.
+    msghdr: Box<libc::msghdr>,
+    /// Whether the multishot request is still active.
+    armed: bool,
+}
+
+impl UringReceiver {
+    /// Create a receiver on `socket` with `buffers` buffers (rounded up to a
+    /// power of two) for datagrams of up to `buffer_size` bytes each.
+    pub fn new(socket: RawFd, buffers: usize, buffer_size: usize) -> io::Result<Self> {
+        let entries = buffers.clamp(1, MAX_BUFFERS).next_power_of_two();
+
+        // SAFETY:
+        // An all-zero `msghdr` is valid, only the lengths are used.
+        let mut msghdr: Box<libc::msghdr> = Box::new(unsafe { zeroed() });
+        msghdr.msg_namelen = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        // The control buffer is empty, with the space for the messages reserved.
+        msghdr.msg_controllen = receive::control_buffer().capacity() as _;
+        let header_len = RECVMSG_OUT_LEN + msghdr.msg_namelen as usize + msghdr.msg_controllen;
+
+        // Every buffer can complete once before the completions are reaped.
+        // Deferring the receive work until the ring is entered batches it.
+        let ring = IoUring::builder()
+            .setup_cqsize(entries as u32 * 2)
+            .setup_single_issuer()
+            .setup_defer_taskrun()
+            .build(8)?;
+        let buffer_ring = BufferRing::new(entries, header_len + buffer_size)?;
+        // SAFETY:
+        // The ring memory stays valid until the buffer ring is dropped, which
+        // happens after the io_uring is dropped.
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                buffer_ring.entries.as_ptr() as u64,
+                entries as u16,
+                BUFFER_GROUP,
+                0,
+            )?;
+        }
+
+        Ok(Self {
+            ring,
+            buffer_ring,
+            socket,
+            msghdr,
+            armed: false,
+        })
+    }
+
+    /// Wait up to `timeout` for datagrams and pass every received datagram
+    /// to `handle`. Returns the number of received datagrams.
+    ///
+    /// Must be called from the thread which created the receiver.
+    ///
+    /// Datagrams larger than the buffer size are passed truncated, with
+    /// their original length in [`Metadata::truncated_from`].
+    pub fn recv(
+        &mut self,
+        timeout: Duration,
+        mut handle: impl FnMut(&[u8], &Metadata),
+    ) -> nix::Result<usize> {
+        if !self.armed {
+            let entry = opcode::RecvMsgMulti::new(Fd(self.socket), &*self.msghdr, BUFFER_GROUP)
+                .flags(libc::MSG_TRUNC as u32)
+                .build();
+            // SAFETY:
+            // The `msghdr` outlives the request and the buffers are provided
+            // by the registered buffer ring.
+            unsafe { self.ring.submission().push(&entry) }.map_err(|_| Errno::EBUSY)?;
+            self.armed = true;
+        }
+
+        let timespec = Timespec::from(timeout);
+        let args = SubmitArgs::new().timespec(&timespec);
+        // Waking up for every datagram costs more than the syscalls saved,
+        // so wait until half of the buffers are filled or the timeout.
+        let want = self.buffer_ring.len() / 2;
+        match self.ring.submitter().submit_with_args(want.max(1), &args) {
+            Ok(_) => {}
+            // Timed out or interrupted without completions.
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => {}
+            Err(e) => return Err(errno(&e)),
+        }
+
+        let mut received = 0;
+        let mut error = None;
+        for cqe in self.ring.completion() {
+            if !cqueue::more(cqe.flags()) {
+                // The request ends on errors and once the buffers ran out.
+                self.armed = false;
+            }
+            if cqe.result() < 0 && cqe.result() != -libc::ENOBUFS {
+                error = Some(Errno::from_i32(-cqe.result()));
+            }
+            let Some(id) = cqueue::buffer_select(cqe.flags()) else {
+                continue;
+            };
+
+            if let Ok(out) = RecvMsgOut::parse(self.buffer_ring.buffer(id), &self.msghdr) {
+                received += 1;
+                let meta = metadata(&out);
+                handle(out.payload_data(), &meta);
+            }
+            self.buffer_ring.recycle(id);
+        }
+        self.buffer_ring.publish();
+
+        match error {
+            Some(e) if received == 0 => Err(e),
+            _ => Ok(received),
+        }
+    }
+}
+
+/// Page aligned ring of buffer descriptors followed by the buffers.
+struct BufferRing {
+    entries: NonNull<BufRingEntry>,
+    layout: Layout,
+    buffers: Vec<u8>,
+    buffer_size: usize,
+    /// Tail of the ring as published to the kernel.
+    tail: u16,
+}
+
+impl BufferRing {
+    fn new(entries: usize, buffer_size: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(entries * size_of::<BufRingEntry>(), 4096)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY:
+        // The layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ring_entries) = NonNull::new(ptr.cast::<BufRingEntry>()) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        let mut ring = Self {
+            entries: ring_entries,
+            layout,
+            buffers: vec![0; entries * buffer_size],
+            buffer_size,
+            tail: 0,
+        };
+        for id in 0..entries {
+            ring.recycle(id as u16);
+        }
+        ring.publish();
+        Ok(ring)
+    }
+
+    fn len(&self) -> usize {
+        self.layout.size() / size_of::<BufRingEntry>()
+    }
+
+    fn buffer(&self, id: u16) -> &[u8] {
+        let start = usize::from(id) * self.buffer_size;
+        &self.buffers[start..start + self.buffer_size]
+    }
+
+    /// Hand the buffer `id` back to the kernel at the next [`publish`](Self::publish).
+    fn recycle(&mut self, id: u16) {
+        let mask = (self.len() - 1) as u16;
+        // SAFETY:
+        // The index is masked to the number of ring entries.
+        let entry = unsafe { &mut *self.entries.as_ptr().add(usize::from(self.tail & mask)) };
+        let offset = usize::from(id) * self.buffer_size;
+        entry.set_addr(self.buffers.as_ptr() as u64 + offset as u64);
+        entry.set_len(self.buffer_size as u32);
+        entry.set_bid(id);
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    /// Make the recycled buffers visible to the kernel.
+    fn publish(&self) {
+        // SAFETY:
+        // The tail overlays the reserved field of the first entry, which the
+        // kernel reads concurrently.
+        let tail = unsafe { &*BufRingEntry::tail(self.entries.as_ptr()).cast::<AtomicU16>() };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for BufferRing {
+    fn drop(&mut self) {
+        // SAFETY:
+        // The memory was allocated with this layout in `new`.
+        unsafe { alloc::dealloc(self.entries.as_ptr().cast(), self.layout) };
+    }
+}
+
+fn errno(error: &io::Error) -> Errno {
+    Errno::from_i32(error.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/// Collect the metadata of a datagram from its address and control messages.
+fn metadata(out: &RecvMsgOut) -> Metadata {
+    let mut meta = Metadata {
+        source: source_address(out.name_data()),
+        truncated_from: out
+            .is_payload_truncated()
+            .then_some(out.incoming_payload_len() as usize),
+        ..Default::default()
+    };
+
+    let control = out.control_data();
+    let header_len = cmsg_align(size_of::<libc::cmsghdr>());
+    let mut offset = 0;
+    while offset + header_len <= control.len() {
+        // SAFETY:
+        // The header lies within the control data and is read unaligned.
+        let cmsg: libc::cmsghdr = unsafe { ptr::read_unaligned(control[offset..].as_ptr().cast()) };
+        let len = cmsg.cmsg_len as usize;
+        if len < header_len || offset + len > control.len() {
+            break;
+        }
+        let data = &control[offset + header_len..offset + len];
+
+        // SAFETY:
+        // The control messages are read as integers and plain C structs.
+        match (cmsg.cmsg_level, cmsg.cmsg_type) {
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMP) => {
+                if let Some(tv) = unsafe { read::<libc::timeval>(data) } {
+                    meta.received_ns = Some(latency::timeval_to_nanos(&TimeVal::from(tv)));
+                }
+            }
+            (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                meta.drop_counter = unsafe { read::<u32>(data) }
+            }
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                if let Some(info) = unsafe { read::<in_pktinfo>(data) } {
+                    meta.set_ipv4_packet_info(&info);
+                }
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                if let Some(info) = unsafe { read::<in6_pktinfo>(data) } {
+                    meta.set_ipv6_packet_info(&info);
+                }
+            }
+            _ => {}
+        }
+        offset += cmsg_align(len);
+    }
+
+    meta
+}
+
+fn source_address(name: &[u8]) -> Option<SocketAddr> {
+    // SAFETY:
+    // An all-zero `sockaddr_storage` is valid.
+    let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+    let len = name.len().min(size_of::<libc::sockaddr_storage>());
+    // SAFETY:
+    // At most the size of the storage is copied into it, and the length
+    // passed to nix is the one of the copied address.
+    let addr = unsafe {
+        ptr::copy_nonoverlapping(name.as_ptr(), ptr::addr_of_mut!(storage).cast(), len);
+        SockaddrStorage::from_raw(ptr::addr_of!(storage).cast(), Some(len as _))
+    }?;
+    to_socket_addr(&addr)
+}
+
+/// Read a `T` from the start of control message data.
+///
+/// # Safety
+///
+/// `T` must be valid for any bit pattern, like integers and the plain C
+/// structs of control messages.
+unsafe fn read<T: Copy>(data: &[u8]) -> Option<T> {
+    // SAFETY:
+    // The length is checked and the value is read unaligned.
+    (data.len() >= size_of::<T>()).then(|| unsafe { ptr::read_unaligned(data.as_ptr().cast()) })
+}
+
+fn cmsg_align(len: usize) -> usize {
+    let align = size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
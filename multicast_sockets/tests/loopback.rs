@@ -0,0 +1,539 @@
+//! End-to-end tests of the sender and receiver building blocks on `lo`.
+//!
+//! The tests use IPv4, as Linux does not route IPv6 multicast via `lo`. Each
+//! test uses its own group and port, since the tests run in parallel and the
+//! receiver sockets are bound to the unspecified address.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::fd::RawFd,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use multicast_sockets::{
+    crypto::{session_id, PresharedKey, ReplayGuard, Sealer},
+    fanout::{bind_worker_socket, Steering},
+    fragment::{max_chunk_len, Fragmenter, Reassembler},
+    get_interface_by_name,
+    groups::{joined_groups, missing},
+    membership::{self, Membership},
+    nack::{recv_nack, send_nack, NackTracker, RetransmitBuffer},
+    packet::{self, Flags, Header},
+    receive::{self, rmem_max, set_receive_buffer, BatchReceiver, Metadata},
+    send::{self, BatchSender, PacketInfo},
+    stats::{Arrival, SequenceTracker},
+};
+use nix::{errno::Errno, net::if_::if_nametoindex, sys::socket::AddressFamily, unistd::close};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn loopback() -> u32 {
+    if_nametoindex("lo").unwrap()
+}
+
+fn group(last_octet: u8, port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(239, 255, 20, last_octet).into(), port)
+}
+
+/// Receiver socket which is closed on drop.
+struct Receiver {
+    socket: RawFd,
+    batch: BatchReceiver,
+}
+
+impl Receiver {
+    fn bind(port: u16, groups: &[SocketAddr]) -> Self {
+        Self::join(
+            receive::bind_socket(AddressFamily::Inet, port).unwrap(),
+            groups,
+        )
+    }
+
+    fn join(socket: RawFd, groups: &[SocketAddr]) -> Self {
+        for group in groups {
+            membership::join(socket, &Membership::any_source(group.ip(), loopback())).unwrap();
+        }
+        Self {
+            socket,
+            batch: BatchReceiver::new(16, 256),
+        }
+    }
+
+    /// Receive until `count` datagrams arrived or `timeout` elapsed.
+    fn recv(&mut self, count: usize, timeout: Duration) -> Vec<(Vec<u8>, Metadata)> {
+        let mut received = Vec::new();
+        let deadline = Instant::now() + timeout;
+        while received.len() < count && Instant::now() < deadline {
+            let result = self.batch.recv(self.socket, |payload, meta| {
+                received.push((payload.to_vec(), *meta));
+            });
+            match result {
+                Ok(_) => {}
+                Err(Errno::EAGAIN) => sleep(Duration::from_millis(1)),
+                Err(e) => panic!("failed to receive: {e}"),
+            }
+        }
+        received
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let _ = close(self.socket);
+    }
+}
+
+/// Sender of encoded packets which is closed on drop.
+struct Sender {
+    sender: BatchSender,
+}
+
+impl Sender {
+    fn new(group: SocketAddr) -> Self {
+        let socket = send::bind_socket(AddressFamily::Inet, 0).unwrap();
+        send::set_multicast_loop(socket, AddressFamily::Inet, true).unwrap();
+        Self {
+            sender: BatchSender::new(socket, group, PacketInfo::new(LOCALHOST, loopback())),
+        }
+    }
+
+    fn send(&self, stream_id: u16, sequences: &[u32]) {
+        let datagrams: Vec<_> = sequences
+            .iter()
+            .map(|&sequence| {
+                let header = Header {
+                    stream_id,
+                    sequence,
+                    ..Default::default()
+                };
+                let mut datagram = Vec::new();
+                packet::encode(&header, b"payload", &mut datagram);
+                datagram
+            })
+            .collect();
+        assert_eq!(self.sender.send(&datagrams), Ok(datagrams.len()));
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let _ = close(self.sender.socket());
+    }
+}
+
+fn decode_sequences(received: &[(Vec<u8>, Metadata)]) -> Vec<u32> {
+    received
+        .iter()
+        .map(|(datagram, _)| packet::decode(datagram).unwrap().header.sequence)
+        .collect()
+}
+
+#[test]
+fn delivers_packets_with_metadata() {
+    let group = group(1, 30201);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+
+    sender.send(3, &[0, 1, 2, 3]);
+    let received = receiver.recv(4, Duration::from_secs(1));
+
+    assert_eq!(decode_sequences(&received), [0, 1, 2, 3]);
+    for (datagram, meta) in &received {
+        let packet = packet::decode(datagram).unwrap();
+        assert_eq!(packet.header.stream_id, 3);
+        assert_eq!(packet.payload, b"payload");
+        assert_eq!(meta.group, Some(group.ip()));
+        assert_eq!(meta.interface_index, Some(loopback()));
+        assert_eq!(meta.source.map(|source| source.ip()), Some(LOCALHOST));
+        assert!(meta.received_ns.is_some());
+    }
+}
+
+#[test]
+fn tracks_gaps_and_reordering() {
+    let group = group(2, 30202);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+
+    sender.send(0, &[0, 1, 3, 2, 5, 5, 6]);
+    let received = receiver.recv(7, Duration::from_secs(1));
+
+    let mut tracker = SequenceTracker::new();
+    for sequence in decode_sequences(&received) {
+        tracker.record(sequence);
+    }
+    let stats = tracker.stats();
+    assert_eq!(stats.received, 6);
+    assert_eq!(stats.lost, 1);
+    assert_eq!(stats.reordered, 1);
+    assert_eq!(stats.duplicates, 1);
+    assert_eq!(stats.max_reorder_depth, 1);
+}
+
+#[test]
+fn receives_multiple_groups_on_one_socket() {
+    let groups = [group(3, 30203), group(4, 30203)];
+    let mut receiver = Receiver::bind(30203, &groups);
+    let senders = groups.map(Sender::new);
+
+    senders[0].send(0, &[0, 1]);
+    senders[1].send(1, &[0, 1, 2]);
+    let received = receiver.recv(5, Duration::from_secs(1));
+
+    assert_eq!(received.len(), 5);
+    for (datagram, meta) in &received {
+        let stream_id = packet::decode(datagram).unwrap().header.stream_id;
+        assert_eq!(meta.group, Some(groups[usize::from(stream_id)].ip()));
+    }
+}
+
+#[test]
+fn stops_receiving_after_leave() {
+    let groups = [group(5, 30205), group(6, 30205)];
+    let mut receiver = Receiver::bind(30205, &groups);
+    let senders = groups.map(Sender::new);
+
+    membership::leave(
+        receiver.socket,
+        &Membership::any_source(groups[0].ip(), loopback()),
+    )
+    .unwrap();
+    senders[0].send(0, &[0, 1]);
+    senders[1].send(1, &[0]);
+
+    // Wait for any late datagram of the left group as well.
+    let received = receiver.recv(usize::MAX, Duration::from_millis(200));
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].1.group, Some(groups[1].ip()));
+}
+
+#[test]
+fn repairs_gaps_with_nacks() {
+    let group = group(8, 30208);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+
+    let mut buffer = RetransmitBuffer::new(16);
+    let mut datagrams = Vec::new();
+    for sequence in 0..6 {
+        let header = Header {
+            stream_id: 4,
+            sequence,
+            ..Default::default()
+        };
+        let mut datagram = Vec::new();
+        packet::encode(&header, b"payload", &mut datagram);
+        buffer.insert(sequence, &datagram);
+        datagrams.push(datagram);
+    }
+    // Packets 2 and 3 are lost on the way.
+    let sent = [&datagrams[..2], &datagrams[4..]].concat();
+    assert_eq!(sender.sender.send(&sent), Ok(4));
+
+    let mut tracker = SequenceTracker::new();
+    let mut nacks = NackTracker::new(Duration::from_millis(10), 3);
+    let received = receiver.recv(4, Duration::from_secs(1));
+    assert_eq!(decode_sequences(&received), [0, 1, 4, 5]);
+    for sequence in decode_sequences(&received) {
+        if let Arrival::Gap(gap) = tracker.record(sequence) {
+            nacks.on_gap(sequence, gap);
+        }
+        nacks.on_received(sequence);
+    }
+
+    let nack = nacks.poll(4, Instant::now()).unwrap();
+    let source = received[0].1.source.unwrap();
+    send_nack(receiver.socket, &nack, source).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let (requested, _) = loop {
+        if let Some(request) = recv_nack(sender.sender.socket()).unwrap() {
+            break request;
+        }
+        assert!(Instant::now() < deadline, "no NACK received");
+        sleep(Duration::from_millis(1));
+    };
+    let requested = requested.unwrap();
+    assert_eq!(requested, nack);
+
+    let retransmissions: Vec<_> = requested
+        .sequences()
+        .map(|sequence| {
+            let packet = buffer.retransmission(sequence).unwrap();
+            let mut datagram = Vec::new();
+            packet::encode(&packet.header, packet.payload, &mut datagram);
+            datagram
+        })
+        .collect();
+    assert_eq!(sender.sender.send(&retransmissions), Ok(2));
+
+    let received = receiver.recv(2, Duration::from_secs(1));
+    assert_eq!(decode_sequences(&received), [2, 3]);
+    for (datagram, _) in &received {
+        let packet = packet::decode(datagram).unwrap();
+        assert!(packet.header.flags.contains(Flags::RETRANSMIT));
+        assert!(tracker.recover(packet.header.sequence));
+        nacks.on_received(packet.header.sequence);
+    }
+    assert_eq!(tracker.stats().lost, 0);
+    assert_eq!(tracker.stats().recovered, 2);
+    assert!(nacks
+        .poll(4, Instant::now() + Duration::from_secs(1))
+        .is_none());
+}
+
+#[test]
+fn rejects_replays_from_another_source() {
+    let group = group(14, 30215);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+    let attacker = Sender::new(group);
+
+    let key = PresharedKey::new(&[7; 32]);
+    let mut sealer = Sealer::new(key.clone()).unwrap();
+    let header = Header {
+        stream_id: 3,
+        sequence: 1,
+        flags: Flags::ENCRYPTED,
+        ..Default::default()
+    };
+    let mut datagram = Vec::new();
+    sealer.seal(&header, b"payload", &mut datagram);
+    assert_eq!(sender.sender.send(&[datagram.clone()]), Ok(1));
+    assert_eq!(attacker.sender.send(&[datagram]), Ok(1));
+
+    let received = receiver.recv(2, Duration::from_secs(1));
+    assert_eq!(received.len(), 2);
+    assert_ne!(received[0].1.source, received[1].1.source);
+    let mut guard = ReplayGuard::default();
+    let mut plaintext = Vec::new();
+    let accepted: Vec<_> = received
+        .iter()
+        .map(|(datagram, _)| {
+            let packet = packet::decode(datagram).unwrap();
+            let opened = key.open(datagram, &packet, &mut plaintext).unwrap();
+            assert_eq!(opened.payload, b"payload");
+            let session = session_id(packet.payload).unwrap();
+            guard.accept(&opened.header, session)
+        })
+        .collect();
+    assert_eq!(accepted, [true, false]);
+}
+
+#[test]
+fn reassembles_fragmented_messages() {
+    let group = group(9, 30209);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+
+    let mut header = Header {
+        flags: Flags::FRAGMENT,
+        ..Default::default()
+    };
+    let mut fragmenter = Fragmenter::new(max_chunk_len(256, true, &header));
+    let message: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+    let mut datagrams = Vec::new();
+    fragmenter
+        .split(&message, |fragment| {
+            let mut datagram = Vec::new();
+            packet::encode(&header, fragment, &mut datagram);
+            datagrams.push(datagram);
+            header.sequence += 1;
+        })
+        .unwrap();
+    assert!(datagrams.iter().all(|datagram| datagram.len() + 28 <= 256));
+    // The fragments may arrive in any order.
+    datagrams.reverse();
+    assert_eq!(sender.sender.send(&datagrams), Ok(datagrams.len()));
+
+    let received = receiver.recv(datagrams.len(), Duration::from_secs(1));
+    assert_eq!(received.len(), datagrams.len());
+    let mut reassembler = Reassembler::default();
+    let mut messages = Vec::new();
+    for (datagram, _) in &received {
+        let packet = packet::decode(datagram).unwrap();
+        assert!(packet.header.flags.contains(Flags::FRAGMENT));
+        if let Some(message) = reassembler.push(packet.payload, Instant::now()).unwrap() {
+            messages.push(message);
+        }
+    }
+    assert_eq!(messages, [message]);
+    assert_eq!(reassembler.memory(), 0);
+}
+
+#[test]
+fn reports_truncated_datagrams() {
+    let group = group(10, 30210);
+    let mut receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+
+    // The receiver buffers hold 256 bytes.
+    let datagrams = [vec![1; 256], vec![2; 300]];
+    assert_eq!(sender.sender.send(&datagrams), Ok(2));
+    let received = receiver.recv(2, Duration::from_secs(1));
+
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].0, datagrams[0]);
+    assert_eq!(received[0].1.truncated_from, None);
+    assert_eq!(received[1].0, &datagrams[1][..256]);
+    assert_eq!(received[1].1.truncated_from, Some(300));
+}
+
+#[test]
+fn reads_back_receive_buffer_size() {
+    let socket = receive::bind_socket(AddressFamily::Inet, 0).unwrap();
+    let rmem_max = rmem_max().unwrap();
+
+    let buffer = set_receive_buffer(socket, 4096).unwrap();
+    assert_eq!(buffer.effective, 2 * 4096.min(rmem_max));
+    assert_eq!(buffer.is_clamped(), 4096 > rmem_max);
+
+    let buffer = set_receive_buffer(socket, rmem_max + 1).unwrap();
+    assert_eq!(buffer.effective, 2 * rmem_max);
+    assert!(buffer.is_clamped());
+
+    close(socket).unwrap();
+}
+
+#[test]
+fn lists_joined_groups() {
+    let membership = Membership::any_source(group(13, 0).ip(), loopback());
+    let memberships = [membership];
+    let socket = receive::bind_socket(AddressFamily::Inet, 0).unwrap();
+
+    membership::join(socket, &membership).unwrap();
+    let joined = joined_groups().unwrap();
+    let group = joined
+        .iter()
+        .find(|joined| joined.group == membership.group)
+        .unwrap();
+    assert_eq!(group.interface_index, loopback());
+    assert_eq!(group.interface_name, "lo");
+    assert_eq!(group.users, 1);
+    assert!(missing(&memberships, &joined).is_empty());
+
+    membership::leave(socket, &membership).unwrap();
+    assert_eq!(
+        missing(&memberships, &joined_groups().unwrap()),
+        [&membership]
+    );
+    close(socket).unwrap();
+}
+
+#[test]
+fn steers_streams_to_one_worker_each() {
+    let group = group(12, 30214);
+    let mut workers: Vec<_> = (0..2)
+        .map(|worker| {
+            let socket = bind_worker_socket(
+                AddressFamily::Inet,
+                group.port(),
+                Steering::Stream,
+                2,
+                worker,
+            )
+            .unwrap();
+            Receiver::join(socket, &[group])
+        })
+        .collect();
+    let sender = Sender::new(group);
+
+    for stream_id in 0..8 {
+        sender.send(stream_id, &[0, 1, 2, 3]);
+    }
+    let received: Vec<_> = workers
+        .iter_mut()
+        .map(|worker| worker.recv(16, Duration::from_secs(1)))
+        .collect();
+
+    let mut seen = Vec::new();
+    for datagrams in &received {
+        // Consecutive stream ids of one sender alternate between two workers.
+        assert_eq!(datagrams.len(), 16);
+        let mut streams: Vec<_> = datagrams
+            .iter()
+            .map(|(datagram, _)| packet::decode(datagram).unwrap().header.stream_id)
+            .collect();
+        streams.dedup();
+        assert_eq!(streams.len(), 4);
+        seen.extend(streams);
+    }
+    seen.sort();
+    assert_eq!(seen, (0..8).collect::<Vec<_>>());
+}
+
+#[test]
+fn finds_interfaces_by_name() {
+    let multicast = pnet_datalink::interfaces()
+        .into_iter()
+        .filter(pnet_datalink::NetworkInterface::is_multicast);
+    for interface in multicast {
+        let found = get_interface_by_name(&interface.name).unwrap();
+        assert_eq!(found.index, interface.index);
+    }
+
+    assert!(get_interface_by_name("does-not-exist0").is_none());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_receiver_yields_datagrams() {
+    use multicast_sockets::async_receiver::MulticastReceiver;
+
+    let group = group(7, 30207);
+    let membership = Membership::any_source(group.ip(), loopback());
+    let mut receiver =
+        MulticastReceiver::bind(AddressFamily::Inet, group.port(), &[membership]).unwrap();
+    let sender = Sender::new(group);
+
+    sender.send(0, &[0, 1, 2]);
+    for sequence in 0..3 {
+        let datagram = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = packet::decode(&datagram.payload).unwrap();
+        assert_eq!(packet.header.sequence, sequence);
+        assert_eq!(datagram.meta.group, Some(group.ip()));
+    }
+}
+
+#[cfg(feature = "io-uring")]
+#[test]
+fn uring_receiver_delivers_packets_with_metadata() {
+    use multicast_sockets::uring::UringReceiver;
+
+    let group = group(11, 30211);
+    let receiver = Receiver::bind(group.port(), &[group]);
+    let sender = Sender::new(group);
+    // Fewer buffers than datagrams, which ends the multishot request until
+    // the buffers are recycled.
+    let mut uring = UringReceiver::new(receiver.socket, 4, 256).unwrap();
+
+    let sequences: Vec<_> = (0..32).collect();
+    sender.send(5, &sequences);
+    assert_eq!(sender.sender.send(&[vec![2; 300]]), Ok(1));
+
+    let mut received = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while received.len() < 33 && Instant::now() < deadline {
+        uring
+            .recv(Duration::from_millis(10), |payload, meta| {
+                received.push((payload.to_vec(), *meta));
+            })
+            .unwrap();
+    }
+
+    let (truncated, packets) = received.split_last().unwrap();
+    assert_eq!(decode_sequences(packets), sequences);
+    for (_, meta) in packets {
+        assert_eq!(meta.group, Some(group.ip()));
+        assert_eq!(meta.interface_index, Some(loopback()));
+        assert_eq!(meta.source.map(|source| source.ip()), Some(LOCALHOST));
+        assert!(meta.received_ns.is_some());
+        assert_eq!(meta.truncated_from, None);
+    }
+    assert_eq!(truncated.0, [2; 256]);
+    assert_eq!(truncated.1.truncated_from, Some(300));
+}